@@ -0,0 +1,486 @@
+use crate::Model;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Read},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+};
+
+/// Reply of `MockBackend` once its scripted replies are used up
+const MOCK_REPLY: &str = "I don't know.";
+
+/// Server generating the text, `LLM` builds the prompts and keeps the history
+pub trait InferenceBackend: Send {
+    /// Completes `request.prompt`, or replies to `request.messages` for chat APIs
+    fn generate(&self, request: &GenerationRequest) -> String;
+
+    /// Same as `generate` but calls `on_token` with each piece of text as soon as it's generated
+    ///
+    /// Returns the whole text. Backends that can't stream call `on_token` once with the whole text.
+    fn generate_stream(
+        &self,
+        request: &GenerationRequest,
+        on_token: &mut dyn FnMut(&str),
+    ) -> String {
+        let text = self.generate(request);
+
+        on_token(&text);
+
+        text
+    }
+}
+
+/// A message of the conversation for chat APIs, `role` is "system", "user" or "assistant"
+#[derive(Clone, Debug, Serialize)]
+pub struct Message {
+    pub role: &'static str,
+    pub content: String,
+}
+
+/// What to generate and how, each backend maps it to its own API
+#[derive(Clone, Debug)]
+pub struct GenerationRequest {
+    /// Formatted with the template of the model, for completion APIs
+    pub prompt: String,
+    /// The same prompt as messages, for chat APIs
+    pub messages: Vec<Message>,
+    /// Maximum number of tokens generated
+    pub max_length: u32,
+    pub temperature: f32,
+    /// The temperature varies by up to this much, only KoboldCpp supports it
+    pub dynamic_temperature: f32,
+    pub min_p: f32,
+    /// 1.0 disables it
+    pub repetition_penalty: f32,
+    /// Generation stops at the first of these
+    pub stop: Vec<String>,
+}
+
+/// https://lite.koboldai.net/koboldcpp_api
+pub struct KoboldCpp {
+    url: String,
+    /// `None` when connected to a server started by someone else
+    process: Option<Child>,
+}
+
+impl KoboldCpp {
+    /// Starts `./resources/koboldcpp_rocm.exe` with `model` and waits for it to be loaded
+    pub fn spawn(model: Model) -> KoboldCpp {
+        let mut process = Command::new("./resources/koboldcpp_rocm.exe")
+            .stderr(Stdio::null())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            // Kobold doesn't parse the "onready" config correctly from the config file
+            // .args(&["--config", model.config()])
+            .args(model.process_config())
+            .spawn()
+            .unwrap();
+
+        // Wait for the model to load and get the "done" message from kobold
+        let mut stdout = process.stdout.take().unwrap();
+
+        let mut buf = [0u8; 4];
+        stdout.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"done");
+
+        KoboldCpp {
+            url: "http://localhost:5001".to_string(),
+            process: Some(process),
+        }
+    }
+
+    /// Server already running at `url`, e.g. "http://localhost:5001"
+    pub fn connect(url: impl Into<String>) -> KoboldCpp {
+        KoboldCpp {
+            url: url.into(),
+            process: None,
+        }
+    }
+
+    fn body(request: &GenerationRequest) -> Value {
+        json!({
+            "prompt": request.prompt,
+            "min_p": request.min_p,
+            "rep_pen": request.repetition_penalty,
+            "rep_pen_range": 2048,
+            "rep_pen_slope": 0.9,
+            "temperature": request.temperature,
+            "dynatemp_range": request.dynamic_temperature,
+            "stop_sequence": request.stop,
+            "max_length": request.max_length,
+        })
+    }
+}
+
+impl InferenceBackend for KoboldCpp {
+    fn generate(&self, request: &GenerationRequest) -> String {
+        let response: Value = ureq::post(&format!("{}/api/v1/generate", self.url))
+            .send_json(KoboldCpp::body(request))
+            .unwrap()
+            .into_json()
+            .unwrap();
+
+        response["results"][0]["text"].as_str().unwrap().to_string()
+    }
+
+    fn generate_stream(
+        &self,
+        request: &GenerationRequest,
+        on_token: &mut dyn FnMut(&str),
+    ) -> String {
+        let response = ureq::post(&format!("{}/api/extra/generate/stream", self.url))
+            .send_json(KoboldCpp::body(request))
+            .unwrap();
+
+        read_events(response, on_token, |event| event["token"].as_str())
+    }
+}
+
+impl Drop for KoboldCpp {
+    fn drop(&mut self) {
+        let Some(process) = &mut self.process else {
+            return;
+        };
+
+        // `kill` doesn't kill processes spawned by Koboldcpp
+        // so we use taskkill to force it
+        #[cfg(target_os = "windows")]
+        {
+            Command::new("taskkill")
+                .arg("/F")
+                .arg("/T")
+                .arg("/PID")
+                .arg(process.id().to_string())
+                .spawn()
+                .unwrap()
+                .wait()
+                .unwrap();
+        }
+
+        process.kill().unwrap();
+    }
+}
+
+/// llama.cpp's `llama-server`
+///
+/// https://github.com/ggerganov/llama.cpp/tree/master/examples/server
+pub struct LlamaServer {
+    url: String,
+}
+
+impl LlamaServer {
+    /// e.g. "http://localhost:8080"
+    pub fn new(url: impl Into<String>) -> LlamaServer {
+        LlamaServer { url: url.into() }
+    }
+
+    fn body(request: &GenerationRequest, stream: bool) -> Value {
+        json!({
+            "prompt": request.prompt,
+            "n_predict": request.max_length,
+            "temperature": request.temperature,
+            "min_p": request.min_p,
+            "repeat_penalty": request.repetition_penalty,
+            "stop": request.stop,
+            "cache_prompt": true,
+            "stream": stream,
+        })
+    }
+}
+
+impl InferenceBackend for LlamaServer {
+    fn generate(&self, request: &GenerationRequest) -> String {
+        let response: Value = ureq::post(&format!("{}/completion", self.url))
+            .send_json(LlamaServer::body(request, false))
+            .unwrap()
+            .into_json()
+            .unwrap();
+
+        response["content"].as_str().unwrap().to_string()
+    }
+
+    fn generate_stream(
+        &self,
+        request: &GenerationRequest,
+        on_token: &mut dyn FnMut(&str),
+    ) -> String {
+        let response = ureq::post(&format!("{}/completion", self.url))
+            .send_json(LlamaServer::body(request, true))
+            .unwrap();
+
+        read_events(response, on_token, |event| event["content"].as_str())
+    }
+}
+
+/// Any server with an OpenAI compatible `/v1/completions` endpoint, the prompt is sent as is
+pub struct OpenAICompletions {
+    url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl OpenAICompletions {
+    /// `url` without the `/v1`, e.g. "http://localhost:8000"
+    pub fn new(
+        url: impl Into<String>,
+        model: impl Into<String>,
+        api_key: Option<String>,
+    ) -> OpenAICompletions {
+        OpenAICompletions {
+            url: url.into(),
+            model: model.into(),
+            api_key,
+        }
+    }
+
+    fn body(&self, request: &GenerationRequest, stream: bool) -> Value {
+        json!({
+            "model": self.model,
+            "prompt": request.prompt,
+            "max_tokens": request.max_length,
+            "temperature": request.temperature,
+            "stop": request.stop,
+            "stream": stream,
+        })
+    }
+}
+
+impl InferenceBackend for OpenAICompletions {
+    fn generate(&self, request: &GenerationRequest) -> String {
+        let response: Value = openai_post(&self.url, "completions", self.api_key.as_deref())
+            .send_json(self.body(request, false))
+            .unwrap()
+            .into_json()
+            .unwrap();
+
+        response["choices"][0]["text"].as_str().unwrap().to_string()
+    }
+
+    fn generate_stream(
+        &self,
+        request: &GenerationRequest,
+        on_token: &mut dyn FnMut(&str),
+    ) -> String {
+        let response = openai_post(&self.url, "completions", self.api_key.as_deref())
+            .send_json(self.body(request, true))
+            .unwrap();
+
+        read_events(response, on_token, |event| {
+            event["choices"][0]["text"].as_str()
+        })
+    }
+}
+
+/// Any server with an OpenAI compatible `/v1/chat/completions` endpoint
+///
+/// The server applies its own template to the messages, the template of the `Model` isn't used.
+pub struct OpenAIChat {
+    url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl OpenAIChat {
+    /// `url` without the `/v1`, e.g. "https://api.openai.com"
+    pub fn new(
+        url: impl Into<String>,
+        model: impl Into<String>,
+        api_key: Option<String>,
+    ) -> OpenAIChat {
+        OpenAIChat {
+            url: url.into(),
+            model: model.into(),
+            api_key,
+        }
+    }
+
+    fn body(&self, request: &GenerationRequest, stream: bool) -> Value {
+        json!({
+            "model": self.model,
+            "messages": request.messages,
+            "max_tokens": request.max_length,
+            "temperature": request.temperature,
+            "stop": request.stop,
+            "stream": stream,
+        })
+    }
+}
+
+impl InferenceBackend for OpenAIChat {
+    fn generate(&self, request: &GenerationRequest) -> String {
+        let response: Value = openai_post(&self.url, "chat/completions", self.api_key.as_deref())
+            .send_json(self.body(request, false))
+            .unwrap()
+            .into_json()
+            .unwrap();
+
+        response["choices"][0]["message"]["content"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn generate_stream(
+        &self,
+        request: &GenerationRequest,
+        on_token: &mut dyn FnMut(&str),
+    ) -> String {
+        let response = openai_post(&self.url, "chat/completions", self.api_key.as_deref())
+            .send_json(self.body(request, true))
+            .unwrap();
+
+        // the first chunk only has the role
+        read_events(response, on_token, |event| {
+            event["choices"][0]["delta"]["content"].as_str()
+        })
+    }
+}
+
+fn openai_post(url: &str, endpoint: &str, api_key: Option<&str>) -> ureq::Request {
+    let request = ureq::post(&format!("{url}/v1/{endpoint}"));
+
+    match api_key {
+        Some(api_key) => request.set("Authorization", &format!("Bearer {api_key}")),
+        None => request,
+    }
+}
+
+/// Reads a stream of server-sent events or of json lines, `token` gets the text out of each event
+///
+/// Calls `on_token` with each piece of text and returns the whole text.
+/// Lines that aren't json like `event: message` or `data: [DONE]` are skipped.
+fn read_events(
+    response: ureq::Response,
+    on_token: &mut dyn FnMut(&str),
+    token: fn(&Value) -> Option<&str>,
+) -> String {
+    let mut text = String::new();
+
+    for line in BufReader::new(response.into_reader()).lines() {
+        let line = line.unwrap();
+        let data = line.strip_prefix("data:").unwrap_or(&line);
+
+        let Ok(event) = serde_json::from_str::<Value>(data) else {
+            continue;
+        };
+
+        if let Some(token) = token(&event).filter(|token| !token.is_empty()) {
+            on_token(token);
+            text.push_str(token);
+        }
+    }
+
+    text
+}
+
+/// https://github.com/ollama/ollama/blob/main/docs/api.md
+///
+/// The prompt is sent raw, already formatted with the template of the `Model`.
+pub struct Ollama {
+    url: String,
+    model: String,
+}
+
+impl Ollama {
+    /// e.g. "http://localhost:11434" and "mistral"
+    pub fn new(url: impl Into<String>, model: impl Into<String>) -> Ollama {
+        Ollama {
+            url: url.into(),
+            model: model.into(),
+        }
+    }
+
+    fn body(&self, request: &GenerationRequest, stream: bool) -> Value {
+        json!({
+            "model": self.model,
+            "prompt": request.prompt,
+            "raw": true,
+            "stream": stream,
+            "options": {
+                "num_predict": request.max_length,
+                "temperature": request.temperature,
+                "min_p": request.min_p,
+                "repeat_penalty": request.repetition_penalty,
+                "stop": request.stop,
+            },
+        })
+    }
+}
+
+impl InferenceBackend for Ollama {
+    fn generate(&self, request: &GenerationRequest) -> String {
+        let response: Value = ureq::post(&format!("{}/api/generate", self.url))
+            .send_json(self.body(request, false))
+            .unwrap()
+            .into_json()
+            .unwrap();
+
+        response["response"].as_str().unwrap().to_string()
+    }
+
+    /// Ollama streams a json object per line
+    fn generate_stream(
+        &self,
+        request: &GenerationRequest,
+        on_token: &mut dyn FnMut(&str),
+    ) -> String {
+        let response = ureq::post(&format!("{}/api/generate", self.url))
+            .send_json(self.body(request, true))
+            .unwrap();
+
+        read_events(response, on_token, |event| event["response"].as_str())
+    }
+}
+
+/// Replies with scripted answers without any server, to run conversations offline
+#[derive(Default)]
+pub struct MockBackend {
+    replies: Mutex<VecDeque<String>>,
+    requests: Arc<Mutex<Vec<GenerationRequest>>>,
+}
+
+impl MockBackend {
+    /// Replies in order, then "I don't know." once they're used up
+    pub fn new(replies: impl IntoIterator<Item = impl Into<String>>) -> MockBackend {
+        MockBackend {
+            replies: Mutex::new(replies.into_iter().map(Into::into).collect()),
+            requests: Arc::default(),
+        }
+    }
+
+    /// Requests received so far, oldest first
+    ///
+    /// Take it before giving the backend to an `LLM` to look at the prompts it built.
+    pub fn requests(&self) -> Arc<Mutex<Vec<GenerationRequest>>> {
+        self.requests.clone()
+    }
+}
+
+impl InferenceBackend for MockBackend {
+    fn generate(&self, request: &GenerationRequest) -> String {
+        self.requests.lock().unwrap().push(request.clone());
+
+        self.replies
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| MOCK_REPLY.to_string())
+    }
+
+    /// Streams the reply a word at a time
+    fn generate_stream(
+        &self,
+        request: &GenerationRequest,
+        on_token: &mut dyn FnMut(&str),
+    ) -> String {
+        let reply = self.generate(request);
+
+        for word in reply.split_inclusive(' ') {
+            on_token(word);
+        }
+
+        reply
+    }
+}
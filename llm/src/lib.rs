@@ -1,59 +1,124 @@
-use shared::END_OF_SENTENCE;
-use std::{
-    collections::VecDeque,
-    fmt::Write,
-    io::Read,
-    process::{Child, Command, Stdio},
+mod backend;
+
+pub use backend::{
+    GenerationRequest, InferenceBackend, KoboldCpp, LlamaServer, Message, MockBackend, Ollama,
+    OpenAIChat, OpenAICompletions,
 };
+pub use shared::Speaker;
+
+use rag::{GroundedAnswer, TextGenerator, RAG};
+use shared::{CHARACTERS_PER_TOKEN, END_OF_SENTENCE};
+use std::{collections::VecDeque, fmt::Write};
 use tts::TTS;
 
+/// Maximum number of tokens in a reply
+const MAX_LENGTH: u32 = 100;
+/// Tokens kept for the user's next message
+const MESSAGE_MARGIN: usize = 512;
+
 pub struct LLM {
-    process: Child,
+    backend: Box<dyn InferenceBackend>,
     model: Model,
     history: History,
-    tts: TTS,
+    /// Started the first time text to speech is enabled
+    tts: Option<TTS>,
     tts_enabled: bool,
 }
 
 impl LLM {
+    /// Starts KoboldCpp with `model`, text to speech is enabled
     pub fn init(model: Model, user: impl Into<String>, assistant: impl Into<String>) -> LLM {
-        let mut process = Command::new("./resources/koboldcpp_rocm.exe")
-            .stderr(Stdio::null())
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            // Kobold doesn't parse the "onready" config correctly from the config file
-            // .args(&["--config", model.config()])
-            .args(model.process_config())
-            .spawn()
-            .unwrap();
-
-        // Wait for the model to load and get the "done" message from kobold
-        let mut stdout = process.stdout.take().unwrap();
-
-        let mut buf = [0u8; 4];
-        stdout.read_exact(&mut buf).unwrap();
-        assert_eq!(&buf, b"done");
+        let mut llm = LLM::with_backend(Box::new(KoboldCpp::spawn(model)), model, user, assistant);
+        llm.enable_tts();
 
-        let tts = TTS::new();
+        llm
+    }
 
+    /// Conversation generated by `backend`, `model` decides the prompt template and the context size
+    ///
+    /// Text to speech is disabled, e.g. to run a conversation offline with a `MockBackend`.
+    pub fn with_backend(
+        backend: Box<dyn InferenceBackend>,
+        model: Model,
+        user: impl Into<String>,
+        assistant: impl Into<String>,
+    ) -> LLM {
         LLM {
-            process,
+            backend,
             model,
             history: History::new(user, assistant),
-            tts,
-            tts_enabled: true,
+            tts: None,
+            tts_enabled: false,
         }
     }
 
     pub fn send(&self) -> String {
-        let mut response: GenerateResponse = ureq::post("http://localhost:5001/api/v1/generate")
-            .send_json(&GenerateRequest::new(&self.model, &self.history))
-            .unwrap()
-            .into_json()
-            .unwrap();
+        let reply = self.reply();
+
+        self.say(&reply);
+
+        reply
+    }
+
+    /// Same as `send` but the reply is checked against the numbered passages of `rag`, see `RAG::set_grounded`
+    ///
+    /// The `[n]` markers aren't read aloud.
+    pub fn send_grounded(&self, rag: &RAG) -> GroundedAnswer {
+        let answer = rag.ground(&self.reply());
+
+        self.say(&answer.plain_text());
+
+        answer
+    }
+
+    /// Same as `send` but `on_token` gets the reply a sentence at a time as it's generated, e.g. to print it
+    ///
+    /// Each sentence is read aloud as soon as it ends instead of waiting for the whole reply.
+    /// Put together, the text given to `on_token` is the reply returned: the stop markers and the
+    /// unfinished last sentence that `send` cuts are never given.
+    pub fn send_stream(&self, mut on_token: impl FnMut(&str)) -> String {
+        let mut text = String::new();
+        // bytes of `text` already given to `on_token` and read aloud
+        let mut shown = 0;
+
+        self.backend.generate_stream(&self.request(), &mut |token| {
+            text.push_str(token);
+
+            // the end of a sentence is only known once the next word starts, "3.5" or "e.g" don't end one
+            let unshown = &text[shown..];
+            let end = unshown
+                .char_indices()
+                .zip(unshown.chars().skip(1))
+                .filter(|((_, c), next)| END_OF_SENTENCE.contains(c) && next.is_whitespace())
+                .map(|((i, c), _)| shown + i + c.len_utf8())
+                .last();
+
+            if let Some(end) = end.filter(|end| !text[shown..*end].trim().is_empty()) {
+                on_token(&text[shown..end]);
+                self.say(&text[shown..end]);
+                shown = end;
+            }
+        });
+
+        // the stop markers are after the last sentence, `clean_reply` only keeps its citations
+        let reply = self.clean_reply(&text);
+
+        let leading = text.len() - text.trim_start().len();
+        if let Some(rest) = reply.get(shown.saturating_sub(leading)..) {
+            if !rest.is_empty() {
+                on_token(rest);
+                self.say(rest);
+            }
+        }
 
-        let reply = response.results.remove(0).text;
+        reply
+    }
+
+    fn reply(&self) -> String {
+        self.clean_reply(&self.backend.generate(&self.request()))
+    }
 
+    fn clean_reply(&self, reply: &str) -> String {
         let mut reply = reply
             .trim_end_matches("</s>")
             .trim_end_matches("<|im_end|>")
@@ -61,22 +126,98 @@ impl LLM {
             .trim_end_matches(&format!("\n{}", &self.history.user))
             .trim();
 
-        // Cut the last unfinished sentence
-        let i = reply.rfind(END_OF_SENTENCE).unwrap();
-        reply = &reply[..=i].trim_end();
-
-        if self.tts_enabled {
-            self.tts.say(reply);
+        // Cut the last unfinished sentence, citations after the last sentence are kept
+        // a reply without any end of sentence is kept whole, cutting it would leave nothing
+        if let Some((i, c)) = reply
+            .char_indices()
+            .rev()
+            .find(|(_, c)| END_OF_SENTENCE.contains(c))
+        {
+            let end = i + c.len_utf8();
+            let citations = reply[end..]
+                .chars()
+                .all(|c| c.is_ascii_digit() || "[], ".contains(c));
+            if !citations {
+                reply = reply[..end].trim_end();
+            }
         }
 
         reply.to_string()
     }
 
+    fn say(&self, text: &str) {
+        if let (true, Some(tts)) = (self.tts_enabled, &self.tts) {
+            tts.say(text);
+        }
+    }
+
+    /// Request for the next reply of the conversation
+    fn request(&self) -> GenerationRequest {
+        GenerationRequest {
+            prompt: self.model.template().prompt(&self.history),
+            messages: self.history.messages(),
+            max_length: MAX_LENGTH,
+            temperature: 1.25,
+            dynamic_temperature: 0.75,
+            min_p: 0.1,
+            repetition_penalty: 1.07,
+            stop: vec![
+                format!("{}:", &self.history.user),
+                format!("\n{}", &self.history.user),
+                "### Instruction:".to_string(),
+                "<|im_end|>".to_string(),
+            ],
+        }
+    }
+
+    /// Request for a single instruction outside of the conversation
+    fn instruct_request(&self, instruction: &str, max_length: u32) -> GenerationRequest {
+        GenerationRequest {
+            prompt: self.model.template().instruct(instruction),
+            messages: vec![Message {
+                role: "user",
+                content: instruction.to_string(),
+            }],
+            max_length,
+            temperature: 0.3,
+            dynamic_temperature: 0.0,
+            min_p: 0.1,
+            repetition_penalty: 1.0,
+            stop: vec![
+                "</s>".to_string(),
+                "### Instruction:".to_string(),
+                "<|im_end|>".to_string(),
+            ],
+        }
+    }
+
+    /// Tokens left for the RAG context once the prompt, history and reply are accounted for
+    pub fn context_budget(&self) -> usize {
+        // the templates can't build a prompt without history
+        // the system prompt is small enough to fit in the margin
+        let used = if self.history.history.is_empty() {
+            0
+        } else {
+            let prompt = self.model.template().prompt(&self.history);
+
+            (prompt.len() - self.history.context.len()) / CHARACTERS_PER_TOKEN
+        };
+
+        self.model
+            .context_size()
+            .saturating_sub(used + MAX_LENGTH as usize + MESSAGE_MARGIN)
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
     pub fn history_mut(&mut self) -> &mut History {
         &mut self.history
     }
 
     pub fn enable_tts(&mut self) {
+        self.tts.get_or_insert_with(TTS::new);
         self.tts_enabled = true;
     }
 
@@ -85,80 +226,32 @@ impl LLM {
     }
 
     pub fn skip_tts(&self) {
-        self.tts.skip();
+        if let Some(tts) = &self.tts {
+            tts.skip();
+        }
     }
 
     pub fn stop_tts(&self) {
-        self.tts.stop();
-    }
-}
-
-impl Drop for LLM {
-    fn drop(&mut self) {
-        // `kill` doesn't kill processes spawned by Koboldcpp
-        // so we use taskkill to force it
-        #[cfg(target_os = "windows")]
-        {
-            Command::new("taskkill")
-                .arg("/F")
-                .arg("/T")
-                .arg("/PID")
-                .arg(self.process.id().to_string())
-                .spawn()
-                .unwrap()
-                .wait()
-                .unwrap();
+        if let Some(tts) = &self.tts {
+            tts.stop();
         }
-
-        self.process.kill().unwrap();
     }
 }
 
-/// https://lite.koboldai.net/koboldcpp_api
-#[derive(serde::Serialize)]
-struct GenerateRequest {
-    prompt: String,
-    min_p: f32,
-    rep_pen: f32,
-    rep_pen_range: u32,
-    rep_pen_slope: f32,
-    temperature: f32,
-    dynatemp_range: f32,
-    stop_sequence: Vec<String>,
-    max_length: u32,
-}
+impl TextGenerator for LLM {
+    fn generate(&self, prompt: &str, max_length: u32) -> String {
+        let reply = self
+            .backend
+            .generate(&self.instruct_request(prompt, max_length));
 
-impl GenerateRequest {
-    fn new(model: &Model, history: &History) -> GenerateRequest {
-        GenerateRequest {
-            prompt: model.template().prompt(history),
-            min_p: 0.1,
-            rep_pen: 1.07,
-            rep_pen_range: 2048,
-            rep_pen_slope: 0.9,
-            temperature: 1.25,
-            dynatemp_range: 0.75,
-            stop_sequence: vec![
-                format!("{}:", &history.user),
-                format!("\n{}", &history.user),
-                "### Instruction:".to_string(),
-                "<|im_end|>".to_string(),
-            ],
-            max_length: 100,
-        }
+        reply
+            .trim_end_matches("</s>")
+            .trim_end_matches("<|im_end|>")
+            .trim()
+            .to_string()
     }
 }
 
-#[derive(serde::Deserialize)]
-struct GenerateResponse {
-    results: Vec<GenerateResult>,
-}
-
-#[derive(serde::Deserialize)]
-struct GenerateResult {
-    text: String,
-}
-
 #[derive(Clone, Copy)]
 pub enum Model {
     /// https://huggingface.co/TheBloke/Mistral-7B-Instruct-v0.2-GGUF
@@ -198,7 +291,11 @@ impl Model {
         }
     }
 
-    fn process_config(&self) -> impl IntoIterator<Item = &'static str> {
+    fn context_size(&self) -> usize {
+        32768
+    }
+
+    fn process_config(&self) -> impl IntoIterator<Item = String> {
         let model = match self {
             Model::Mistral => "./resources/mistral-7b-instruct-v0.2.Q6_K.gguf",
             Model::Mixtral => "./resources/mixtral-8x7b-instruct-v0.1.Q3_K_M.gguf",
@@ -223,12 +320,13 @@ impl Model {
             | Model::BondBurger => "15",
             Model::YiV2 => "20",
         };
+        let context_size = self.context_size().to_string();
 
         [
             "--model",
             model,
             "--contextsize",
-            "32768",
+            &context_size,
             "--skiplauncher",
             "--usecublas",
             "normal",
@@ -242,6 +340,7 @@ impl Model {
             "echo done",
             // "--debug",
         ]
+        .map(str::to_string)
     }
 }
 
@@ -264,15 +363,20 @@ impl History {
         }
     }
 
-    pub fn add(&mut self, s: impl Into<String>, speaker: Speaker) {
+    /// Returns the oldest entries dropped to make room, e.g. to keep them in a `rag::Memory`
+    pub fn add(&mut self, s: impl Into<String>, speaker: Speaker) -> Vec<(Speaker, String)> {
         self.history.push_back(HistoryEntry {
             speaker,
             text: s.into(),
         });
 
         if self.history.len() > 20 {
-            self.history.pop_front();
-            self.history.pop_front();
+            self.history
+                .drain(..2)
+                .map(|entry| (entry.speaker, entry.text))
+                .collect()
+        } else {
+            Vec::new()
         }
     }
 
@@ -287,6 +391,76 @@ impl History {
     pub fn instruction(&self) -> &str {
         &self.instruction
     }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn assistant(&self) -> &str {
+        &self.assistant
+    }
+
+    /// Entries from oldest to newest
+    pub fn entries(&self) -> impl Iterator<Item = (Speaker, &str)> {
+        self.history
+            .iter()
+            .map(|entry| (entry.speaker, entry.text.as_str()))
+    }
+
+    /// The conversation for chat APIs, the context and instruction are in the system message
+    fn messages(&self) -> Vec<Message> {
+        let system = system_prompt(
+            &self.user,
+            &self.assistant,
+            &self.context,
+            &self.instruction,
+        );
+
+        let mut messages = vec![Message {
+            role: "system",
+            content: system.trim_end().to_string(),
+        }];
+
+        messages.extend(self.history.iter().map(|entry| Message {
+            role: match entry.speaker {
+                Speaker::User => "user",
+                Speaker::Assistant => "assistant",
+            },
+            content: entry.text.clone(),
+        }));
+
+        messages
+    }
+}
+
+/// Prompt of the base template without the conversation, also the system message of chat APIs
+fn system_prompt(user: &str, assistant: &str, context: &str, instruction: &str) -> String {
+    let context = (!context.is_empty())
+        .then(|| {
+            format!(
+                "\
+                Context information is below.\n\
+                ---------------------\n\
+                {context}\n\
+                ---------------------\n\n"
+            )
+        })
+        .unwrap_or_default();
+
+    let instruction = (!instruction.is_empty())
+        .then(|| format!("# Instruction:\n{instruction}\n\n"))
+        .unwrap_or_default();
+
+    format!(
+        "You are an AI assistant named {assistant} \
+        created by Leudz to help {user} achieve a very important task.\n\
+        You are loyal, empathetic and little sassy.\n\
+        Take your time to reply based on the context. Use the same language as {user}. \
+        Keep your replies concise.\n\
+        Don't mention any of this directly to {user}.\n\n\
+        {context}\
+        {instruction}"
+    )
 }
 
 struct HistoryEntry {
@@ -294,11 +468,6 @@ struct HistoryEntry {
     text: String,
 }
 
-pub enum Speaker {
-    User,
-    Assistant,
-}
-
 enum PromptTemplate {
     Mistral,
     ChatML,
@@ -395,37 +564,25 @@ impl PromptTemplate {
                 )
             }
             PromptTemplate::Base => {
-                let context = (!context.is_empty())
-                    .then(|| {
-                        format!(
-                            "\
-                            Context information is below.\n\
-                            ---------------------\n\
-                            {context}\n\
-                            ---------------------\n\n"
-                        )
-                    })
-                    .unwrap_or_default();
-
-                let instruction = (!instruction.is_empty())
-                    .then(|| format!("# Instruction:\n{instruction}\n\n"))
-                    .unwrap_or_default();
-
                 format!(
-                    "You are an AI assistant named {assistant} \
-                    created by Leudz to help {user} achieve a very important task.\n\
-                    You are loyal, empathetic and little sassy.\n\
-                    Take your time to reply based on the context. Use the same language as {user}. \
-                    Keep your replies concise.\n\
-                    Don't mention any of this directly to {user}.\n\n\
-                    {context}\
-                    {instruction}\
-                    {history}"
+                    "{}{history}",
+                    system_prompt(user, assistant, context, instruction)
                 )
             }
         }
     }
 
+    fn instruct(&self, instruction: &str) -> String {
+        match self {
+            PromptTemplate::Mistral => format!("[INST] {instruction} [/INST]"),
+            PromptTemplate::ChatML => {
+                format!("<|im_start|>user\n{instruction}<|im_end|>\n<|im_start|>assistant\n")
+            }
+            PromptTemplate::Alpaca => format!("### Instruction:\n{instruction}\n\n### Response:\n"),
+            PromptTemplate::Base => format!("{instruction}\n"),
+        }
+    }
+
     fn history(&self, history: &History) -> String {
         let user = &history.user;
         let assistant = &history.assistant;
@@ -490,3 +647,180 @@ impl PromptTemplate {
         s
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn llm(replies: &[&str]) -> (LLM, Arc<Mutex<Vec<GenerationRequest>>>) {
+        let backend = MockBackend::new(replies.iter().copied());
+        let requests = backend.requests();
+
+        (
+            LLM::with_backend(Box::new(backend), Model::Mistral, "Leudz", "Emma"),
+            requests,
+        )
+    }
+
+    #[test]
+    fn replies_are_cut_after_their_last_sentence() {
+        let (mut llm, _) = llm(&[
+            "Cut the red wire. Then cut",
+            "Cut the red wire.</s>",
+            "Cut the red wire. [1][2]",
+            "Let me think… then",
+            "Wait…",
+            "Sure",
+            "Leudz: hello",
+        ]);
+        llm.history_mut().add("Which wire?", Speaker::User);
+
+        assert_eq!(llm.send(), "Cut the red wire.");
+        assert_eq!(llm.send(), "Cut the red wire.");
+        // citations after the last sentence are kept
+        assert_eq!(llm.send(), "Cut the red wire. [1][2]");
+        assert_eq!(llm.send(), "Let me think…");
+        assert_eq!(llm.send(), "Wait…");
+        // nothing to cut
+        assert_eq!(llm.send(), "Sure");
+        assert_eq!(llm.send(), "Leudz: hello");
+        // the mock backend ran out of replies
+        assert_eq!(llm.send(), "I don't know.");
+    }
+
+    #[test]
+    fn streamed_replies_match_the_history() {
+        let (mut llm, requests) = llm(&[
+            "Cut the red wire. It's the 3.5 cm one! Then cut",
+            "Sure. </s>",
+            "Wait… [1]",
+            "  Keep\n\ntalking.\n",
+        ]);
+        llm.history_mut().add("Which wire?", Speaker::User);
+
+        let send_stream = || {
+            let mut tokens = Vec::new();
+            let reply = llm.send_stream(|token| tokens.push(token.to_string()));
+
+            assert_eq!(tokens.concat().trim_start(), reply);
+
+            (reply, tokens)
+        };
+
+        assert_eq!(
+            send_stream(),
+            (
+                "Cut the red wire. It's the 3.5 cm one!".to_string(),
+                vec![
+                    "Cut the red wire.".to_string(),
+                    " It's the 3.5 cm one!".to_string()
+                ]
+            )
+        );
+        assert_eq!(send_stream().0, "Sure.");
+        assert_eq!(send_stream().0, "Wait… [1]");
+        assert_eq!(send_stream().0, "Keep\n\ntalking.");
+
+        assert_eq!(requests.lock().unwrap().len(), 4);
+
+        let reply = send_stream().0;
+        llm.history_mut().add(reply, Speaker::Assistant);
+        assert_eq!(
+            llm.history().entries().last(),
+            Some((Speaker::Assistant, "I don't know."))
+        );
+    }
+
+    #[test]
+    fn requests_are_built_from_the_history() {
+        let (mut llm, requests) = llm(&["Cut the red wire.", "The second one."]);
+
+        llm.history_mut().set_context("There are three wires.");
+        llm.history_mut()
+            .set_instruction("Help me defuse the bomb.");
+        llm.history_mut().add("Which wire?", Speaker::User);
+
+        let reply = llm.send();
+        llm.history_mut().add(reply, Speaker::Assistant);
+        llm.history_mut().add("Which one is red?", Speaker::User);
+        llm.send();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+
+        let request = &requests[1];
+        assert_eq!(request.max_length, MAX_LENGTH);
+        assert!(request.stop.contains(&"Leudz:".to_string()));
+
+        for text in [
+            "There are three wires.",
+            "Help me defuse the bomb.",
+            "Which wire?",
+            "Cut the red wire.",
+            "Which one is red?",
+        ] {
+            assert!(request.prompt.contains(text));
+        }
+
+        let messages = request
+            .messages
+            .iter()
+            .map(|message| (message.role, message.content.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(messages[0].0, "system");
+        assert!(messages[0].1.contains("There are three wires."));
+        assert_eq!(
+            messages[1..],
+            [
+                ("user", "Which wire?"),
+                ("assistant", "Cut the red wire."),
+                ("user", "Which one is red?"),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_oldest_turns_are_dropped_from_the_history() {
+        let (mut llm, _) = llm(&[]);
+
+        for turn in 0..10 {
+            assert!(llm
+                .history_mut()
+                .add(format!("Question {turn}"), Speaker::User)
+                .is_empty());
+            assert!(llm
+                .history_mut()
+                .add(format!("Answer {turn}"), Speaker::Assistant)
+                .is_empty());
+        }
+
+        let forgotten = llm.history_mut().add("Question 10", Speaker::User);
+        assert_eq!(
+            forgotten,
+            [
+                (Speaker::User, "Question 0".to_string()),
+                (Speaker::Assistant, "Answer 0".to_string()),
+            ]
+        );
+
+        let entries = llm.history().entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 19);
+        assert_eq!(entries[0], (Speaker::User, "Question 1"));
+        assert_eq!(entries[18], (Speaker::User, "Question 10"));
+    }
+
+    #[test]
+    fn instructions_are_outside_of_the_conversation() {
+        let (mut llm, requests) = llm(&["A summary.</s>"]);
+        llm.history_mut().add("Which wire?", Speaker::User);
+
+        assert_eq!(llm.generate("Summarize the passages.", 200), "A summary.");
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].max_length, 200);
+        assert!(!requests[0].prompt.contains("Which wire?"));
+        assert_eq!(requests[0].messages.len(), 1);
+        assert_eq!(requests[0].messages[0].content, "Summarize the passages.");
+    }
+}
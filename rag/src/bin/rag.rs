@@ -0,0 +1,199 @@
+use rag::{BertEmbedder, Index};
+use std::path::Path;
+
+const USAGE: &str = "\
+Usage: rag [--database <path>] [--english] <command> [<arguments>]
+
+Manages the database used by the assistant, ./resources/database.data by default.
+New databases are encoded with a multilingual model, questions can be asked in
+another language than the documents. With --english they use a smaller English
+only model. Databases remember their model, reencode changes it.
+
+Commands:
+    ingest <paths...>       adds pdf and markdown files, folders are walked recursively
+    crawl <url>             adds the main content of a web page
+    search <query>          shows how documents are ranked for the query
+    entities <query>        shows the entities of the query and the ones related to them
+    stats                   documents, vectors, vocabulary size and average length
+    remove <id|source>      removes a document, or all the documents of a source
+    export <file> [--embeddings]
+                            writes the documents as JSON lines, - for stdout
+    import <file>           adds the documents of an export
+    compact                 renumbers the documents and rebuilds the indices without the removed ones
+    verify                  checks that vectors, word counts and hashes match the documents
+    backups                 lists the previous saves that can be restored
+    restore <generation>    goes back to a previous save
+    reencode                encodes the documents again with the multilingual model, or the English one";
+
+fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    let database = match args.iter().position(|arg| arg == "--database") {
+        Some(position) if position + 1 < args.len() => {
+            let database = args.remove(position + 1);
+            args.remove(position);
+
+            Some(database)
+        }
+        Some(_) => {
+            println!("{USAGE}");
+            return;
+        }
+        None => None,
+    };
+
+    let english = match args.iter().position(|arg| arg == "--english") {
+        Some(position) => {
+            args.remove(position);
+
+            true
+        }
+        None => false,
+    };
+
+    let embedder = || {
+        Box::new(if english {
+            BertEmbedder::new()
+        } else {
+            BertEmbedder::multilingual()
+        })
+    };
+
+    let Some((command, arguments)) = args.split_first() else {
+        println!("{USAGE}");
+        return;
+    };
+
+    let index = || {
+        let index = match (&database, english) {
+            (Some(database), false) => Index::open_at(database),
+            (None, false) => Index::open(),
+            (Some(database), true) => Index::open_at_with_embedder(database, embedder()),
+            (None, true) => Index::open_with_embedder(embedder()),
+        };
+
+        index.unwrap_or_else(|error| {
+            println!("Can't open the database: {error}");
+            std::process::exit(1);
+        })
+    };
+
+    match (command.as_str(), arguments) {
+        ("ingest", paths) if !paths.is_empty() => {
+            let index = index();
+
+            for path in paths {
+                ingest(&index, Path::new(path));
+            }
+
+            index.save();
+        }
+        ("crawl", [url]) => {
+            let index = index();
+
+            index.add_website(url);
+            index.save();
+        }
+        ("search", query) if !query.is_empty() => {
+            println!("{}", index().explain(&query.join(" ")));
+        }
+        ("entities", query) if !query.is_empty() => {
+            for card in index().entity_cards(&query.join(" ")) {
+                println!("{card}");
+            }
+        }
+        ("stats", []) => println!("{}", index().stats()),
+        ("remove", [document]) => {
+            let index = index();
+
+            let documents = match document.parse() {
+                Ok(id) => vec![id],
+                Err(_) => index.documents_from(document),
+            };
+
+            let removed = documents
+                .into_iter()
+                .filter(|&id| index.remove_document(id))
+                .count();
+
+            println!("{removed} documents removed");
+
+            if removed > 0 {
+                index.save();
+            }
+        }
+        ("export", [file, options @ ..])
+            if options.iter().all(|option| option == "--embeddings") =>
+        {
+            let index = index();
+            let with_embeddings = !options.is_empty();
+
+            let count = if file == "-" {
+                index.export_jsonl(std::io::stdout().lock(), with_embeddings)
+            } else {
+                index.export_jsonl(
+                    std::io::BufWriter::new(std::fs::File::create(file).unwrap()),
+                    with_embeddings,
+                )
+            };
+
+            // stdout only gets the documents so it can be piped
+            eprintln!("{count} documents exported");
+        }
+        ("import", [file]) => {
+            let index = index();
+
+            let count =
+                index.import_jsonl(std::io::BufReader::new(std::fs::File::open(file).unwrap()));
+
+            println!("{count} documents imported");
+
+            if count > 0 {
+                index.save();
+            }
+        }
+        ("compact", []) => {
+            index().compact();
+        }
+        ("verify", []) => println!("{}", index().verify()),
+        ("backups", []) => {
+            for generation in index().backups() {
+                println!("{generation}");
+            }
+        }
+        ("restore", [generation]) => match generation.parse() {
+            Ok(generation) if index().restore(generation) => {
+                println!("Database restored to {generation}")
+            }
+            _ => println!("No backup {generation}"),
+        },
+        ("reencode", []) => {
+            let index = match &database {
+                Some(database) => Index::reencode_at(database, embedder()),
+                None => Index::reencode(embedder()),
+            };
+
+            match index {
+                Ok(_) => println!("Documents encoded again"),
+                Err(error) => println!("Can't open the database: {error}"),
+            }
+        }
+        _ => println!("{USAGE}"),
+    }
+}
+
+fn ingest(index: &Index, path: &Path) {
+    if path.is_dir() {
+        let mut entries = std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        for entry in entries {
+            ingest(index, &entry);
+        }
+    } else {
+        index.add_document(path);
+    }
+}
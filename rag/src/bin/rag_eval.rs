@@ -0,0 +1,103 @@
+use rag::{
+    eval::{evaluate, load_qrels, EvalConfig, EvalReport, Ranking},
+    BertEmbedder, Embedder, HashEmbedder, Quantization,
+};
+
+const USAGE: &str = "\
+Usage: rag_eval [--bert] [--k <k>] [<corpus folder> <qrels file>]
+
+Compares retrieval configurations on a corpus.
+Without a corpus the bundled fixture is used.
+
+    --bert      use the real embedding model instead of the deterministic one
+    --k <k>     number of results evaluated for each query, 5 by default";
+
+fn main() {
+    let mut use_bert = false;
+    let mut k = 5;
+    let mut paths = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bert" => use_bert = true,
+            "--k" => {
+                k = match args.next().and_then(|k| k.parse().ok()) {
+                    Some(k) => k,
+                    None => {
+                        println!("{USAGE}");
+                        return;
+                    }
+                }
+            }
+            "--help" | "-h" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ => paths.push(arg),
+        }
+    }
+
+    let (corpus, qrels) = match paths.as_slice() {
+        [] => (
+            concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/eval/corpus").to_string(),
+            concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/eval/qrels.jsonl").to_string(),
+        ),
+        [corpus, qrels] => (corpus.clone(), qrels.clone()),
+        _ => {
+            println!("{USAGE}");
+            return;
+        }
+    };
+
+    let qrels = load_qrels(qrels);
+
+    let mut configs = Vec::new();
+    for (chunk_size, chunk_overlap) in [(20000, 10000), (1000, 200), (300, 60)] {
+        for ranking in [
+            Ranking::Bm25,
+            Ranking::Embeddings,
+            Ranking::Hybrid { threshold: 1.0 },
+            Ranking::Hybrid { threshold: 0.3 },
+        ] {
+            configs.push(EvalConfig {
+                ranking,
+                chunk_size,
+                chunk_overlap,
+                quantization: Quantization::None,
+                rescore: false,
+            });
+        }
+    }
+
+    // recall and memory of the quantized indices against the full precision ones above
+    for ranking in [Ranking::Embeddings, Ranking::Hybrid { threshold: 0.3 }] {
+        for (quantization, rescore) in [
+            (Quantization::Int8, false),
+            (Quantization::Int8, true),
+            (Quantization::Binary, false),
+            (Quantization::Binary, true),
+        ] {
+            configs.push(EvalConfig {
+                ranking,
+                chunk_size: 300,
+                chunk_overlap: 60,
+                quantization,
+                rescore,
+            });
+        }
+    }
+
+    println!("{} queries, k = {k}", qrels.len());
+    println!("{}", EvalReport::header());
+
+    for config in &configs {
+        let embedder: Box<dyn Embedder> = if use_bert {
+            Box::new(BertEmbedder::new())
+        } else {
+            Box::new(HashEmbedder::default())
+        };
+
+        println!("{}", evaluate(&corpus, &qrels, config, k, embedder));
+    }
+}
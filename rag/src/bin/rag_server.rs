@@ -0,0 +1,50 @@
+use rag::{server::Server, Index};
+use std::sync::Arc;
+
+const USAGE: &str = "\
+Usage: rag_server [--database <path>] [--address <address>]
+
+Serves the database over HTTP, see `rag::server::Server` for the routes.
+
+    --database <path>       ./resources/database.data by default
+    --address <address>     127.0.0.1:5002 by default";
+
+fn main() {
+    let mut database = None;
+    let mut address = "127.0.0.1:5002".to_string();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "--database" | "--address" => args.next(),
+            _ => None,
+        };
+
+        match (arg.as_str(), value) {
+            ("--database", Some(value)) => database = Some(value),
+            ("--address", Some(value)) => address = value,
+            _ => {
+                println!("{USAGE}");
+                return;
+            }
+        }
+    }
+
+    let index = match database {
+        Some(database) => Index::open_at(database),
+        None => Index::open(),
+    };
+
+    let index = match index {
+        Ok(index) => index,
+        Err(error) => {
+            println!("Can't open the database: {error}");
+            return;
+        }
+    };
+
+    let server = Server::bind(Arc::new(index), &address);
+    println!("Listening on http://{}", server.address());
+
+    server.run();
+}
@@ -0,0 +1,254 @@
+use crate::segment::{self, Bytes};
+use fst::{IntoStreamer, Streamer};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
+    path::Path,
+    sync::Arc,
+};
+
+/// Start of a cache file
+const MAGIC: &[u8; 4] = b"RAGC";
+
+/// Where the parts of a saved cache are, written before the last 8 bytes holding its length
+#[derive(Serialize, Deserialize)]
+struct CacheIndex {
+    /// Dimensions of the embeddings of each model
+    dimensions: HashMap<String, usize>,
+    /// Position of the fst from key to the offset of the embedding
+    keys: Range<usize>,
+}
+
+/// Embeddings of the cache file, read through the memory map
+struct SavedEmbeddings {
+    file: Arc<Mmap>,
+    keys: fst::Map<Bytes>,
+    dimensions: HashMap<String, usize>,
+}
+
+/// Embeddings of sentences already encoded, by model id and sha256 of the sentence
+///
+/// Encoding is by far the slowest part of adding a document, re-adding a document after a small
+/// edit only encodes the sentences that changed. The saved embeddings stay memory-mapped, only the
+/// ones added since the last save are in memory.
+#[derive(Default)]
+pub(crate) struct EmbeddingCache {
+    saved: Option<SavedEmbeddings>,
+    /// By model id then sha256
+    added: HashMap<String, HashMap<String, Vec<f32>>>,
+    /// Keys of the saved embeddings pruned since the last save
+    removed: HashSet<Vec<u8>>,
+}
+
+fn key(model: &str, hash: &str) -> Vec<u8> {
+    format!("{model}\0{hash}").into_bytes()
+}
+
+/// Model id and sha256 of a key
+fn split_key(key: &[u8]) -> (&str, &str) {
+    let key = std::str::from_utf8(key).unwrap();
+
+    key.split_once('\0').unwrap()
+}
+
+impl EmbeddingCache {
+    /// Empty if there's no cache at `path` or it can't be read, the cache can always be rebuilt
+    pub(crate) fn load(path: impl AsRef<Path>) -> EmbeddingCache {
+        let Ok(file) = segment::map_segment(path.as_ref()) else {
+            return EmbeddingCache::default();
+        };
+
+        match SavedEmbeddings::read(file) {
+            Some(saved) => EmbeddingCache {
+                saved: Some(saved),
+                ..EmbeddingCache::default()
+            },
+            None => {
+                println!("Unreadable embedding cache, starting an empty one");
+
+                EmbeddingCache::default()
+            }
+        }
+    }
+
+    /// Writes the saved embeddings that weren't pruned and the added ones to a new file replacing `path`
+    pub(crate) fn save(&mut self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+
+        if self.added.is_empty() && self.removed.is_empty() && self.saved.is_some() {
+            return;
+        }
+
+        let temporary_path = path.with_extension("cache.tmp");
+        let mut file = BufWriter::new(File::create(&temporary_path).unwrap());
+        file.write_all(MAGIC).unwrap();
+
+        let mut len = MAGIC.len();
+        let mut offsets = BTreeMap::new();
+        let mut dimensions = HashMap::new();
+
+        let mut write = |key: Vec<u8>, bytes: &[u8]| {
+            file.write_all(bytes).unwrap();
+            offsets.insert(key, len as u64);
+            len += bytes.len();
+        };
+
+        if let Some(saved) = &self.saved {
+            let mut stream = saved.keys.stream();
+            while let Some((key, offset)) = stream.next() {
+                let (model, hash) = split_key(key);
+
+                let is_replaced = self
+                    .added
+                    .get(model)
+                    .is_some_and(|added| added.contains_key(hash));
+                if self.removed.contains(key) || is_replaced {
+                    continue;
+                }
+
+                let size = saved.dimensions[model] * std::mem::size_of::<f32>();
+                dimensions.insert(model.to_string(), saved.dimensions[model]);
+                write(
+                    key.to_vec(),
+                    &saved.file[offset as usize..offset as usize + size],
+                );
+            }
+        }
+
+        for (model, embeddings) in &self.added {
+            for (hash, embedding) in embeddings {
+                dimensions.insert(model.clone(), embedding.len());
+                write(
+                    key(model, hash),
+                    &embedding
+                        .iter()
+                        .flat_map(|value| value.to_le_bytes())
+                        .collect::<Vec<_>>(),
+                );
+            }
+        }
+
+        let keys = fst::Map::from_iter(offsets).unwrap();
+        let keys = keys.as_fst().as_bytes();
+        file.write_all(keys).unwrap();
+
+        let index = bincode::serialize(&CacheIndex {
+            dimensions,
+            keys: len..len + keys.len(),
+        })
+        .unwrap();
+        file.write_all(&index).unwrap();
+        file.write_all(&(index.len() as u64).to_le_bytes()).unwrap();
+
+        file.into_inner().unwrap().sync_all().unwrap();
+
+        // Windows can't replace a file that is still mapped, it's mapped again below
+        self.saved = None;
+
+        std::fs::rename(&temporary_path, path).unwrap();
+        segment::sync_directory(path);
+
+        *self = EmbeddingCache::load(path);
+    }
+
+    pub(crate) fn get(&self, model: &str, hash: &str) -> Option<Vec<f32>> {
+        if let Some(embedding) = self.added.get(model).and_then(|added| added.get(hash)) {
+            return Some(embedding.clone());
+        }
+
+        let saved = self.saved.as_ref()?;
+        let key = key(model, hash);
+        if self.removed.contains(&key) {
+            return None;
+        }
+
+        let offset = saved.keys.get(&key)? as usize;
+        let size = saved.dimensions[model] * std::mem::size_of::<f32>();
+
+        Some(
+            saved.file[offset..offset + size]
+                .chunks_exact(std::mem::size_of::<f32>())
+                .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    pub(crate) fn insert(&mut self, model: &str, hash: String, embedding: Vec<f32>) {
+        self.removed.remove(&key(model, &hash));
+
+        self.added
+            .entry(model.to_string())
+            .or_default()
+            .insert(hash, embedding);
+    }
+
+    /// Removes the embeddings of every model whose sentence hash isn't in `referenced`
+    ///
+    /// Embeddings of other models are kept for the sentences still in use, going back to a model
+    /// doesn't encode everything again. Returns the number of embeddings removed.
+    pub(crate) fn prune(&mut self, referenced: &HashSet<String>) -> usize {
+        let mut removed = 0;
+
+        for embeddings in self.added.values_mut() {
+            let len = embeddings.len();
+            embeddings.retain(|hash, _| referenced.contains(hash));
+            removed += len - embeddings.len();
+        }
+        self.added.retain(|_, embeddings| !embeddings.is_empty());
+
+        if let Some(saved) = &self.saved {
+            let mut stream = saved.keys.keys().into_stream();
+            while let Some(key) = stream.next() {
+                let (_, hash) = split_key(key);
+
+                if !referenced.contains(hash) && self.removed.insert(key.to_vec()) {
+                    removed += 1;
+                }
+            }
+        }
+
+        removed
+    }
+
+    /// Bytes of the embeddings added since the last save, the saved ones are memory-mapped
+    pub(crate) fn memory(&self) -> usize {
+        self.added
+            .values()
+            .flat_map(HashMap::values)
+            .map(|embedding| embedding.len() * std::mem::size_of::<f32>())
+            .sum()
+    }
+}
+
+impl SavedEmbeddings {
+    fn read(file: Arc<Mmap>) -> Option<SavedEmbeddings> {
+        if !file.starts_with(MAGIC) {
+            return None;
+        }
+
+        let index_len =
+            u64::from_le_bytes(file.get(file.len().checked_sub(8)?..)?.try_into().ok()?);
+        let index_start = (file.len() - 8).checked_sub(index_len as usize)?;
+        let index: CacheIndex = bincode::deserialize(&file[index_start..file.len() - 8]).ok()?;
+
+        if index.keys.start > index.keys.end || index.keys.end > index_start {
+            return None;
+        }
+
+        let keys = fst::Map::new(Bytes::Mapped {
+            segment: file.clone(),
+            range: index.keys,
+        })
+        .ok()?;
+
+        Some(SavedEmbeddings {
+            file,
+            keys,
+            dimensions: index.dimensions,
+        })
+    }
+}
@@ -0,0 +1,100 @@
+/// How many sentences are kept before and after a relevant one
+///
+/// A sentence often only makes sense with the one before, e.g. "Otherwise, cut the blue wire."
+const NEIGHBOURS: usize = 1;
+
+/// Keeps the sentences closer than `max_distance` to the query, and their neighbours
+///
+/// `distances` are in the same order as `sentences`. When no sentence is close enough the closest
+/// one is kept. Skipped sentences are replaced by "[...]".
+pub(crate) fn compress(sentences: &[&str], distances: &[f32], max_distance: f32) -> String {
+    let mut keep = vec![false; sentences.len()];
+
+    let mut relevant = distances
+        .iter()
+        .enumerate()
+        .filter(|(_, distance)| **distance <= max_distance)
+        .map(|(sentence, _)| sentence)
+        .collect::<Vec<_>>();
+
+    if relevant.is_empty() {
+        relevant.extend(
+            distances
+                .iter()
+                .enumerate()
+                .min_by(|(_, distance1), (_, distance2)| distance1.total_cmp(distance2))
+                .map(|(sentence, _)| sentence),
+        );
+    }
+
+    for sentence in relevant {
+        let start = sentence.saturating_sub(NEIGHBOURS);
+        let end = (sentence + NEIGHBOURS + 1).min(sentences.len());
+
+        for keep in &mut keep[start..end] {
+            *keep = true;
+        }
+    }
+
+    let mut compressed = String::new();
+    let mut skipped = false;
+    for (sentence, keep) in sentences.iter().zip(keep) {
+        if keep {
+            if skipped && !compressed.is_empty() {
+                compressed.push_str(" [...] ");
+                compressed.push_str(sentence.trim_start());
+            } else {
+                compressed.push_str(sentence);
+            }
+
+            skipped = false;
+        } else {
+            skipped = true;
+        }
+    }
+
+    if skipped {
+        compressed.truncate(compressed.trim_end().len());
+        compressed.push_str(" [...]");
+    }
+
+    compressed.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sentences;
+
+    const TEXT: &str = "Count the wires. If there is no red wire, cut the second one. \
+        Otherwise, cut the last one. The keypad is next. Press the symbols in order.";
+
+    #[test]
+    fn relevant_sentences_are_kept_with_their_neighbours() {
+        let sentences = sentences(TEXT);
+        assert_eq!(sentences.len(), 5);
+
+        assert_eq!(
+            compress(&sentences, &[0.9, 0.9, 0.2, 0.9, 0.9], 0.3),
+            "If there is no red wire, cut the second one. Otherwise, cut the last one. \
+            The keypad is next. [...]"
+        );
+        assert_eq!(
+            compress(&sentences, &[0.1, 0.9, 0.9, 0.9, 0.2], 0.3),
+            "Count the wires. If there is no red wire, cut the second one. [...] \
+            The keypad is next. Press the symbols in order."
+        );
+    }
+
+    #[test]
+    fn the_closest_sentence_is_kept_when_none_is_close_enough() {
+        let sentences = sentences(TEXT);
+
+        assert_eq!(
+            compress(&sentences, &[0.9, 0.8, 0.9, 0.9, 0.5], 0.3),
+            "The keypad is next. Press the symbols in order."
+        );
+        assert_eq!(compress(&sentences, &[0.0; 5], 0.3), TEXT);
+        assert_eq!(compress(&[], &[], 0.3), "");
+    }
+}
@@ -0,0 +1,190 @@
+use crate::Metadata;
+use shared::{CHARACTERS_PER_TOKEN, END_OF_SENTENCE};
+use std::{collections::HashMap, fmt::Write};
+
+pub(crate) struct Passage<'a> {
+    pub(crate) text: &'a str,
+    pub(crate) metadata: &'a Metadata,
+}
+
+/// Characters kept for the number of a passage, "[12] "
+const NUMBER_LENGTH: usize = 5;
+
+/// Builds the context from `passages`, sorted from most to least relevant, without going over `budget` tokens
+///
+/// Passages are added in relevance order, the last one is cut at a sentence boundary if it doesn't fit.
+/// They are then grouped by source and ordered by position so neighbouring passages read naturally.
+/// `numbered` puts "[1]", "[2]"... before the labels in that order.
+///
+/// Returns the context and the position in `passages` of each passage it contains, in context order.
+pub(crate) fn assemble(
+    passages: &[Passage],
+    budget: usize,
+    numbered: bool,
+) -> (String, Vec<usize>) {
+    let mut characters_left = budget * CHARACTERS_PER_TOKEN;
+
+    // (relevance rank, label, text)
+    let mut selected: Vec<(usize, String, &str)> = Vec::new();
+
+    for (rank, passage) in passages.iter().enumerate() {
+        let label = passage.metadata.label();
+        // "\n\n" between passages and "\n" after the label
+        let overhead = label.len() + 3 + if numbered { NUMBER_LENGTH } else { 0 };
+
+        if characters_left <= overhead {
+            break;
+        }
+
+        let characters = characters_left - overhead;
+
+        if passage.text.len() <= characters {
+            characters_left -= overhead + passage.text.len();
+            selected.push((rank, label, passage.text));
+
+            continue;
+        }
+
+        let end = (0..=characters)
+            .rev()
+            .find(|&i| passage.text.is_char_boundary(i))
+            .unwrap();
+
+        if let Some(sentence_end) = passage.text[..end].rfind(END_OF_SENTENCE) {
+            let sentence_end = sentence_end
+                + passage.text[sentence_end..]
+                    .chars()
+                    .next()
+                    .unwrap()
+                    .len_utf8();
+
+            selected.push((rank, label, passage.text[..sentence_end].trim_end()));
+        }
+
+        break;
+    }
+
+    // sources are ordered by their most relevant passage
+    let mut source_ranks: HashMap<&str, usize> = HashMap::new();
+    for (rank, _, _) in &selected {
+        source_ranks
+            .entry(&passages[*rank].metadata.source)
+            .or_insert(*rank);
+    }
+
+    selected.sort_by_key(|(rank, _, _)| {
+        let metadata = passages[*rank].metadata;
+
+        (source_ranks[metadata.source.as_str()], metadata.position)
+    });
+
+    let mut context = String::new();
+    for (number, (_, label, text)) in selected.iter().enumerate() {
+        if !context.is_empty() {
+            context.push_str("\n\n");
+        }
+
+        if numbered {
+            writeln!(context, "{}", numbered_label(number + 1, label)).unwrap();
+        } else if !label.is_empty() {
+            writeln!(context, "{label}").unwrap();
+        }
+
+        context.push_str(text);
+    }
+
+    (context, selected.iter().map(|(rank, _, _)| *rank).collect())
+}
+
+/// `[2] [wires.md, part 1]`
+pub(crate) fn numbered_label(number: usize, label: &str) -> String {
+    if label.is_empty() {
+        format!("[{number}]")
+    } else {
+        format!("[{number}] {label}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(source: &str, position: usize) -> Metadata {
+        Metadata {
+            source: source.to_string(),
+            position,
+            ..Metadata::default()
+        }
+    }
+
+    #[test]
+    fn the_context_never_goes_over_the_budget() {
+        let metadata = [
+            metadata("wires.md", 0),
+            metadata("keypad.md", 3),
+            metadata("", 0),
+            metadata("wires.md", 1),
+        ];
+        let texts = [
+            "Cut the red wire. Then cut the blue one.",
+            "Press the symbols in order. Ça dépend de la colonne… Voilà.",
+            "Short one.",
+            "If there is no red wire, cut the last wire. Otherwise cut the first one.",
+        ];
+        let passages = texts
+            .iter()
+            .zip(&metadata)
+            .map(|(text, metadata)| Passage { text, metadata })
+            .collect::<Vec<_>>();
+
+        for numbered in [false, true] {
+            for budget in 0..60 {
+                let (context, positions) = assemble(&passages, budget, numbered);
+
+                assert!(context.len() <= budget * CHARACTERS_PER_TOKEN, "{context}");
+
+                let mut sorted = positions.clone();
+                sorted.sort_unstable();
+                sorted.dedup();
+                assert_eq!(sorted.len(), positions.len());
+            }
+        }
+    }
+
+    #[test]
+    fn passages_are_grouped_by_source_and_cut_at_a_sentence() {
+        let metadata = [
+            metadata("wires.md", 1),
+            metadata("keypad.md", 0),
+            metadata("wires.md", 0),
+        ];
+        let passages = [
+            Passage {
+                text: "Cut the last wire.",
+                metadata: &metadata[0],
+            },
+            Passage {
+                text: "Press the symbols.",
+                metadata: &metadata[1],
+            },
+            Passage {
+                text: "Count the wires. Then look at their colors.",
+                metadata: &metadata[2],
+            },
+        ];
+
+        let (context, positions) = assemble(&passages, 1000, true);
+        assert_eq!(positions, [2, 0, 1]);
+        assert_eq!(
+            context,
+            "[1] [wires.md, part 1]\nCount the wires. Then look at their colors.\n\n\
+            [2] [wires.md, part 2]\nCut the last wire.\n\n\
+            [3] [keypad.md, part 1]\nPress the symbols."
+        );
+
+        // the third passage only keeps its first sentence
+        let (context, positions) = assemble(&passages, 30, false);
+        assert_eq!(positions, [2, 0, 1]);
+        assert!(context.starts_with("[wires.md, part 1]\nCount the wires.\n\n"));
+    }
+}
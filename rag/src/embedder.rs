@@ -0,0 +1,202 @@
+use rust_bert::pipelines::sentence_embeddings::{
+    SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
+};
+use shared::{END_OF_SENTENCE, SPLIT_WORD};
+use std::{
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+/// Turns sentences into vectors
+///
+/// Vectors have to be normalized, the distance between two vectors is `1 - dot product`.
+/// Embedders are shared by all the conversations of an `Index`, `encode` can be called from several threads.
+pub trait Embedder: Send + Sync {
+    /// Identifies the model, embeddings from different models can't be compared
+    fn id(&self) -> &str;
+    fn encode(&self, sentences: &[&str]) -> Vec<Vec<f32>>;
+}
+
+/// The model can't be used from several threads at once, conversations and searches take turns encoding
+///
+/// Loading a model per thread would let searches encode in parallel, at the cost of its memory for each one.
+/// The model is only loaded by the first `encode`, commands that don't search or add documents don't wait for it.
+pub struct BertEmbedder {
+    model: OnceLock<Mutex<SentenceEmbeddingsModel>>,
+    load: Box<dyn Fn() -> SentenceEmbeddingsModel + Send + Sync>,
+    id: String,
+}
+
+impl BertEmbedder {
+    pub const ENGLISH: &'static str = "AllDistilrobertaV1";
+    pub const MULTILINGUAL: &'static str = "DistiluseBaseMultilingualCased";
+
+    /// English only
+    pub fn new() -> BertEmbedder {
+        BertEmbedder::remote(
+            || SentenceEmbeddingsModelType::AllDistilrobertaV1,
+            BertEmbedder::ENGLISH,
+        )
+    }
+
+    /// Sentences in different languages with the same meaning are close, e.g. a French question
+    /// and the English passage answering it
+    pub fn multilingual() -> BertEmbedder {
+        BertEmbedder::remote(
+            || SentenceEmbeddingsModelType::DistiluseBaseMultilingualCased,
+            BertEmbedder::MULTILINGUAL,
+        )
+    }
+
+    /// Model converted for rust-bert in `directory`, e.g. paraphrase-multilingual-MiniLM-L12-v2
+    ///
+    /// `id` is saved with the database, it has to be the same each time the model is used.
+    pub fn local(directory: impl AsRef<Path>, id: &str) -> BertEmbedder {
+        let directory = directory.as_ref().to_path_buf();
+
+        BertEmbedder {
+            model: OnceLock::new(),
+            load: Box::new(move || {
+                SentenceEmbeddingsBuilder::local(&directory)
+                    .create_model()
+                    .unwrap()
+            }),
+            id: id.to_string(),
+        }
+    }
+
+    /// The embedder of a saved database, `None` for a model that isn't downloaded automatically
+    pub fn from_id(id: &str) -> Option<BertEmbedder> {
+        match id {
+            BertEmbedder::ENGLISH => Some(BertEmbedder::new()),
+            BertEmbedder::MULTILINGUAL => Some(BertEmbedder::multilingual()),
+            _ => None,
+        }
+    }
+
+    /// The model type isn't `Clone`, `model_type` makes it each time it's loaded
+    fn remote(model_type: fn() -> SentenceEmbeddingsModelType, id: &str) -> BertEmbedder {
+        BertEmbedder {
+            model: OnceLock::new(),
+            load: Box::new(move || {
+                SentenceEmbeddingsBuilder::remote(model_type())
+                    .create_model()
+                    .unwrap()
+            }),
+            id: id.to_string(),
+        }
+    }
+}
+
+impl Default for BertEmbedder {
+    fn default() -> BertEmbedder {
+        BertEmbedder::new()
+    }
+}
+
+impl Embedder for BertEmbedder {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Not every model normalizes its embeddings
+    fn encode(&self, sentences: &[&str]) -> Vec<Vec<f32>> {
+        let mut embeddings = self
+            .model
+            .get_or_init(|| Mutex::new((self.load)()))
+            .lock()
+            .unwrap()
+            .encode(sentences)
+            .unwrap();
+
+        for embedding in &mut embeddings {
+            normalize(embedding);
+        }
+
+        embeddings
+    }
+}
+
+/// Deterministic embedder without any model, words are hashed into the dimensions
+///
+/// Only sentences sharing words are close, it's meant to run tests and evaluations offline.
+pub struct HashEmbedder {
+    dimensions: usize,
+    /// Embedders of different sizes can't share embeddings
+    id: String,
+}
+
+impl HashEmbedder {
+    pub fn new(dimensions: usize) -> HashEmbedder {
+        let dimensions = dimensions.max(1);
+
+        HashEmbedder {
+            dimensions,
+            id: format!("HashEmbedder-{dimensions}"),
+        }
+    }
+}
+
+impl Default for HashEmbedder {
+    fn default() -> HashEmbedder {
+        HashEmbedder::new(256)
+    }
+}
+
+impl Embedder for HashEmbedder {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn encode(&self, sentences: &[&str]) -> Vec<Vec<f32>> {
+        sentences
+            .iter()
+            .map(|sentence| {
+                let mut embedding = vec![0.0f32; self.dimensions];
+
+                for word in
+                    sentence.split(|c| END_OF_SENTENCE.contains(&c) || SPLIT_WORD.contains(&c))
+                {
+                    let word = word
+                        .trim_matches(|c: char| !c.is_alphanumeric())
+                        .to_lowercase();
+
+                    if word.is_empty() {
+                        continue;
+                    }
+
+                    let hash = fnv1a(word.as_bytes());
+                    let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+
+                    embedding[(hash % self.dimensions as u64) as usize] += sign;
+                }
+
+                normalize(&mut embedding);
+
+                embedding
+            })
+            .collect()
+    }
+}
+
+fn normalize(embedding: &mut [f32]) {
+    let magnitude = embedding.iter().map(|v| v.powi(2)).sum::<f32>().sqrt();
+
+    if magnitude > 0.0 {
+        for value in embedding {
+            *value /= magnitude;
+        }
+    }
+}
+
+/// The std hasher isn't guaranteed to be stable across Rust versions
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
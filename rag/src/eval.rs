@@ -0,0 +1,386 @@
+use crate::{chunks, Embedder, Index, Metadata, Quantization};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    io::{BufRead, BufReader},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// A query and what should be retrieved for it, one per line in a qrels file
+///
+/// `{"query": "what do I do with a red wire", "sources": ["wires.md"], "spans": ["cut the red wire"]}`
+#[derive(Deserialize)]
+pub struct Qrel {
+    pub query: String,
+    /// File names of the documents answering the query
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// Pieces of text answering the query, compared case insensitively
+    ///
+    /// With spans, a chunk of one of the `sources` only answers the query if it contains one of them.
+    #[serde(default)]
+    pub spans: Vec<String>,
+}
+
+impl Qrel {
+    fn is_relevant(&self, text: &str, metadata: &Metadata) -> bool {
+        let from_source = self.sources.is_empty()
+            || self
+                .sources
+                .iter()
+                .any(|source| source_matches(source, metadata));
+
+        if self.spans.is_empty() {
+            !self.sources.is_empty() && from_source
+        } else {
+            from_source && !self.spans_in(text).is_empty()
+        }
+    }
+
+    fn spans_in(&self, text: &str) -> Vec<&str> {
+        let text = text.to_lowercase();
+
+        self.spans
+            .iter()
+            .filter(|span| text.contains(&span.to_lowercase()))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+fn source_matches(source: &str, metadata: &Metadata) -> bool {
+    metadata.source == source || Path::new(&metadata.source).ends_with(source)
+}
+
+pub fn load_qrels(path: impl AsRef<Path>) -> Vec<Qrel> {
+    let file = std::fs::File::open(path).unwrap();
+
+    BufReader::new(file)
+        .lines()
+        .map(Result::unwrap)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(&line).unwrap())
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Ranking {
+    Bm25,
+    Embeddings,
+    /// What the RAG uses, results further than `threshold` are cut
+    Hybrid {
+        threshold: f32,
+    },
+}
+
+pub struct EvalConfig {
+    pub ranking: Ranking,
+    /// In bytes, see `CHARACTERS_PER_CHUNK`
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub quantization: Quantization,
+    /// Re-ranks the quantized results with the full precision vectors
+    pub rescore: bool,
+}
+
+impl Display for EvalConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ranking = match self.ranking {
+            Ranking::Bm25 => "bm25".to_string(),
+            Ranking::Embeddings => "embeddings".to_string(),
+            Ranking::Hybrid { threshold } => format!("hybrid<{threshold}"),
+        };
+
+        write!(f, "{ranking} {}/{}", self.chunk_size, self.chunk_overlap)?;
+
+        match self.quantization {
+            Quantization::None => {}
+            Quantization::Int8 => f.write_str(" int8")?,
+            Quantization::Binary => f.write_str(" binary")?,
+        }
+
+        if self.rescore && self.quantization != Quantization::None {
+            f.write_str("+rescore")?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct EvalReport {
+    pub config: String,
+    pub k: usize,
+    /// Average recall@k over the queries
+    pub recall: f32,
+    /// Mean Reciprocal Rank
+    pub mrr: f32,
+    /// Average nDCG@k over the queries
+    pub ndcg: f32,
+    /// Search time of each query
+    pub latencies: Vec<Duration>,
+    /// Bytes used by the vectors of the embedding index
+    pub vector_memory: usize,
+    /// Bytes used by the embedding cache, it holds another copy of every vector encoded by the evaluation
+    pub cache_memory: usize,
+}
+
+impl EvalReport {
+    /// `percentile` goes from 0.0 to 100.0
+    pub fn latency_percentile(&self, percentile: f32) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+
+        let mut latencies = self.latencies.clone();
+        latencies.sort_unstable();
+
+        let rank = (percentile / 100.0 * (latencies.len() - 1) as f32).round() as usize;
+
+        latencies[rank.min(latencies.len() - 1)]
+    }
+
+    pub fn header() -> String {
+        format!(
+            "{:<36} {:>9} {:>6} {:>7} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "config", "recall@k", "mrr", "ndcg@k", "p50", "p90", "p99", "memory", "cache"
+        )
+    }
+}
+
+impl Display for EvalReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<36} {:>9.3} {:>6.3} {:>7.3} {:>10.2?} {:>10.2?} {:>10.2?} {:>8.1}KiB {:>8.1}KiB",
+            self.config,
+            self.recall,
+            self.mrr,
+            self.ndcg,
+            self.latency_percentile(50.0),
+            self.latency_percentile(90.0),
+            self.latency_percentile(99.0),
+            self.vector_memory as f32 / 1024.0,
+            self.cache_memory as f32 / 1024.0,
+        )
+    }
+}
+
+/// Indexes all files of the `corpus` folder with `config` and searches each query
+pub fn evaluate(
+    corpus: impl AsRef<Path>,
+    qrels: &[Qrel],
+    config: &EvalConfig,
+    k: usize,
+    embedder: Box<dyn Embedder>,
+) -> EvalReport {
+    let index = Index::in_memory(embedder);
+    index.set_quantization(config.quantization, config.rescore);
+
+    let mut files = std::fs::read_dir(corpus)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    // keep the document indices stable between runs
+    files.sort();
+
+    for path in files {
+        let content = std::fs::read_to_string(&path).unwrap();
+        let source = path.file_name().unwrap().to_string_lossy().to_string();
+
+        for (position, chunk) in chunks(&content, config.chunk_size, config.chunk_overlap)
+            .into_iter()
+            .enumerate()
+        {
+            index.add_with_metadata(
+                chunk,
+                Metadata {
+                    source: source.clone(),
+                    position,
+                    children: Vec::new(),
+                    table: None,
+                    timestamp: None,
+                },
+            );
+        }
+    }
+
+    let mut recall = 0.0;
+    let mut mrr = 0.0;
+    let mut ndcg = 0.0;
+    let mut latencies = Vec::with_capacity(qrels.len());
+
+    for qrel in qrels {
+        let start = Instant::now();
+        let results = index.rank(&qrel.query, config.ranking, k);
+        latencies.push(start.elapsed());
+
+        let database = index.read();
+        let documents = &database.documents;
+
+        let relevant = results
+            .iter()
+            .map(|&index| {
+                let document = &documents[index];
+
+                qrel.is_relevant(&document.text, &document.metadata)
+            })
+            .collect::<Vec<_>>();
+
+        // recall is on what the qrel asks for, the spans if it has any or else the sources,
+        // a source split in many chunks only counts once
+        let mut found = HashSet::new();
+        for (&index, _) in results
+            .iter()
+            .zip(&relevant)
+            .filter(|(_, relevant)| **relevant)
+        {
+            let document = &documents[index];
+
+            if qrel.spans.is_empty() {
+                found.extend(
+                    qrel.sources
+                        .iter()
+                        .filter(|source| source_matches(source, &document.metadata))
+                        .map(String::as_str),
+                );
+            } else {
+                found.extend(qrel.spans_in(&document.text));
+            }
+        }
+
+        let expected = if qrel.spans.is_empty() {
+            qrel.sources.len()
+        } else {
+            qrel.spans.len()
+        };
+        if expected > 0 {
+            recall += found.len() as f32 / expected as f32;
+        }
+
+        if let Some(rank) = relevant.iter().position(|&relevant| relevant) {
+            mrr += 1.0 / (rank as f32 + 1.0);
+        }
+
+        let dcg = relevant
+            .iter()
+            .enumerate()
+            .filter(|(_, relevant)| **relevant)
+            .map(|(rank, _)| 1.0 / (rank as f32 + 2.0).log2())
+            .sum::<f32>();
+
+        let relevant_count = documents
+            .iter()
+            .filter(|(_, document)| qrel.is_relevant(&document.text, &document.metadata))
+            .count();
+
+        let ideal_dcg = (0..relevant_count.min(k))
+            .map(|rank| 1.0 / (rank as f32 + 2.0).log2())
+            .sum::<f32>();
+
+        if ideal_dcg > 0.0 {
+            ndcg += dcg / ideal_dcg;
+        }
+    }
+
+    let query_count = qrels.len().max(1) as f32;
+
+    EvalReport {
+        config: config.to_string(),
+        k,
+        recall: recall / query_count,
+        mrr: mrr / query_count,
+        ndcg: ndcg / query_count,
+        latencies,
+        vector_memory: index.vector_memory(),
+        cache_memory: index.cache_memory(),
+    }
+}
+
+impl Index {
+    /// Indices of the `k` best documents for `query`
+    fn rank(&self, query: &str, ranking: Ranking, k: usize) -> Vec<usize> {
+        let results = match ranking {
+            Ranking::Bm25 => {
+                let mut results = self.read().bm35_plus(query);
+                results.truncate(k);
+                results
+            }
+            Ranking::Embeddings => self.read().search_embeddings(query, k),
+            Ranking::Hybrid { threshold } => self.search_threashold(query, query, k, threshold),
+        };
+
+        results.into_iter().map(|(index, _)| index).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HashEmbedder;
+
+    const CORPUS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/eval/corpus");
+    const QRELS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/eval/qrels.jsonl");
+
+    fn wires() -> Metadata {
+        Metadata {
+            source: "corpus/wires.md".to_string(),
+            ..Metadata::default()
+        }
+    }
+
+    #[test]
+    fn chunks_of_the_source_need_the_span() {
+        let qrel = Qrel {
+            query: "three wires and none of them is red".to_string(),
+            sources: vec!["wires.md".to_string()],
+            spans: vec!["Cut the second wire".to_string()],
+        };
+
+        assert!(qrel.is_relevant("If there are no red wires, cut the second wire.", &wires()));
+        assert!(!qrel.is_relevant("Wires are the lifeblood of electronics.", &wires()));
+        assert!(!qrel.is_relevant(
+            "cut the second wire",
+            &Metadata {
+                source: "button.md".to_string(),
+                ..Metadata::default()
+            }
+        ));
+    }
+
+    #[test]
+    fn evaluates_the_fixtures() {
+        let qrels = load_qrels(QRELS);
+
+        for ranking in [Ranking::Bm25, Ranking::Embeddings] {
+            let config = EvalConfig {
+                ranking,
+                chunk_size: 300,
+                chunk_overlap: 60,
+                quantization: Quantization::None,
+                rescore: false,
+            };
+
+            let report = evaluate(
+                CORPUS,
+                &qrels,
+                &config,
+                5,
+                Box::new(HashEmbedder::default()),
+            );
+
+            assert_eq!(report.latencies.len(), qrels.len());
+            for metric in [report.recall, report.mrr, report.ndcg] {
+                assert!((0.0..=1.0).contains(&metric), "{report}");
+            }
+            assert!(report.vector_memory > 0);
+
+            // the spans use the words of the queries, BM25 finds some of them
+            if let Ranking::Bm25 = ranking {
+                assert!(report.recall > 0.0, "{report}");
+            }
+        }
+    }
+}
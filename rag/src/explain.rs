@@ -0,0 +1,198 @@
+use crate::{fuse, retrieval_top_k, sentences, Index, DISTANCE_THRESHOLD, RAG};
+use std::fmt::Display;
+
+/// Candidates explained for each one retrieved, a few more than what goes in the context
+const EXPLAINED_FACTOR: usize = 2;
+
+/// Why documents were or weren't retrieved for a query
+pub struct Explain {
+    pub query: String,
+    /// Sorted from closest to furthest
+    pub candidates: Vec<Explanation>,
+}
+
+pub struct Explanation {
+    /// Index of the document in the database
+    pub index: usize,
+    pub label: String,
+    /// Contribution of each query word to the bm25 score
+    pub terms: Vec<TermScore>,
+    pub bm25: f32,
+    /// Distance of the closest sentence, `None` when the document isn't in the embeddings results
+    pub embeddings_distance: Option<f32>,
+    /// Sentence that produced the closest vector
+    pub nearest_sentence: Option<String>,
+    /// Distance after merging bm25 and embeddings, what the threshold is compared to
+    pub fused_distance: f32,
+    pub verdict: Verdict,
+}
+
+pub struct TermScore {
+    pub term: String,
+    pub idf: f32,
+    /// Number of times the term appears in the document
+    pub term_frequency: u64,
+    pub score: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    /// With MMR, it's one of the candidates MMR picks the context from
+    Kept,
+    /// The fused distance is over the threshold
+    Threshold,
+    /// Closer documents took all the places
+    TopK,
+}
+
+impl Index {
+    /// Details the search of `query` as a context without MMR retrieves it, see `Explain` and `RAG::explain`
+    pub fn explain(&self, query: &str) -> Explain {
+        self.explain_top_k(query, retrieval_top_k(false))
+    }
+
+    fn explain_top_k(&self, query: &str, top_k: usize) -> Explain {
+        let database = self.read();
+
+        let nearest_sentences = database.search_sentences(query, top_k);
+        let embeddings_results = nearest_sentences
+            .iter()
+            .map(|(key, distance)| (key.document, *distance))
+            .collect::<Vec<_>>();
+        let bm25_results = database.bm35_plus(query);
+
+        let candidates = fuse(bm25_results.clone(), &embeddings_results)
+            .into_iter()
+            .take(top_k * EXPLAINED_FACTOR)
+            .enumerate()
+            .map(|(rank, (index, fused_distance))| {
+                let document = &database.documents[index];
+                let average_word_count = database.average_word_count(document.language);
+
+                let terms = document
+                    .language
+                    .analyze(query)
+                    .into_iter()
+                    .map(|word| {
+                        let idf = database.bm25_idf(&word, document.language);
+                        let (score, term_frequency) =
+                            database.bm25_term(document, &word, idf, average_word_count);
+
+                        TermScore {
+                            term: word,
+                            idf,
+                            term_frequency,
+                            score,
+                        }
+                    })
+                    .collect();
+
+                let bm25 = bm25_results
+                    .iter()
+                    .find(|(bm25_index, _)| *bm25_index == index)
+                    .map(|(_, score)| *score)
+                    .unwrap_or_default();
+
+                let nearest = nearest_sentences
+                    .iter()
+                    .find(|(key, _)| key.document == index);
+
+                let verdict = if rank >= top_k {
+                    Verdict::TopK
+                } else if fused_distance > DISTANCE_THRESHOLD {
+                    Verdict::Threshold
+                } else {
+                    Verdict::Kept
+                };
+
+                Explanation {
+                    index,
+                    label: document.metadata.label(),
+                    terms,
+                    bm25,
+                    embeddings_distance: nearest.map(|(_, distance)| *distance),
+                    nearest_sentence: nearest.and_then(|(key, _)| {
+                        sentences(&document.text)
+                            .get(key.sentence)
+                            .map(|sentence| sentence.trim().to_string())
+                    }),
+                    fused_distance,
+                    verdict,
+                }
+            })
+            .collect();
+
+        Explain {
+            query: query.to_string(),
+            candidates,
+        }
+    }
+}
+
+impl RAG {
+    /// Details the search of `query` with the settings of this conversation, e.g. MMR retrieves more candidates
+    pub fn explain(&self, query: &str) -> Explain {
+        self.index
+            .explain_top_k(query, retrieval_top_k(self.mmr_lambda.is_some()))
+    }
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verdict::Kept => f.write_str("kept"),
+            Verdict::Threshold => write!(f, "cut, distance over {DISTANCE_THRESHOLD}"),
+            Verdict::TopK => f.write_str("cut, closer documents took all the places"),
+        }
+    }
+}
+
+impl Display for Explain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Query: {}", self.query)?;
+        writeln!(
+            f,
+            "{:>3} {:>6} {:<32} {:>8} {:>10} {:>7}  verdict",
+            "#", "doc", "source", "bm25", "embeddings", "fused"
+        )?;
+
+        for (rank, candidate) in self.candidates.iter().enumerate() {
+            let embeddings_distance = candidate
+                .embeddings_distance
+                .map(|distance| format!("{distance:.3}"))
+                .unwrap_or_else(|| "-".to_string());
+
+            writeln!(
+                f,
+                "{:>3} {:>6} {:<32} {:>8.3} {:>10} {:>7.3}  {}",
+                rank + 1,
+                candidate.index,
+                candidate.label,
+                candidate.bm25,
+                embeddings_distance,
+                candidate.fused_distance,
+                candidate.verdict,
+            )?;
+
+            let terms = candidate
+                .terms
+                .iter()
+                .map(|term| {
+                    format!(
+                        "{} (idf {:.2}, tf {}) {:.2}",
+                        term.term, term.idf, term.term_frequency, term.score
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            writeln!(f, "           terms: {terms}")?;
+
+            if let Some(sentence) = &candidate.nearest_sentence {
+                writeln!(f, "           nearest: \"{sentence}\"")?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{sentences, EncodedDocument, Index, Metadata, Quantization, VectorDB, RAG};
+use serde::{Deserialize, Serialize};
+use shared::END_OF_SENTENCE;
+use std::io::{BufRead, Write};
+
+/// A line of a JSONL export, one per document
+///
+/// `{"id": 3, "text": "...", "metadata": {"source": "wires.md", "position": 0}, "hash": "..."}`
+#[derive(Serialize, Deserialize)]
+pub struct DocumentRecord {
+    /// Index of the document in the database it was exported from, a new one is given on import
+    #[serde(default)]
+    pub id: usize,
+    pub text: String,
+    #[serde(default)]
+    pub metadata: Metadata,
+    /// sha256 of `text`
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embeddings: Option<RecordEmbeddings>,
+}
+
+/// Embedding of each sentence of the document, in the order of the text
+#[derive(Serialize, Deserialize)]
+pub struct RecordEmbeddings {
+    /// Embeddings are only imported in a database using the same model, they're encoded again otherwise
+    pub model: String,
+    pub sentences: Vec<Vec<f32>>,
+}
+
+impl Index {
+    /// Writes every document as a line of JSON, returns the number of documents written
+    ///
+    /// With `with_embeddings` the sentence embeddings are included. A quantized index without rescoring
+    /// only has approximations of them, they're left out and the documents are encoded again on import.
+    pub fn export_jsonl(&self, mut writer: impl Write, with_embeddings: bool) -> usize {
+        let database = self.read();
+
+        let with_embeddings = with_embeddings
+            && (database.map.quantization() == Quantization::None
+                || database.full_vectors.is_some());
+        let model = database.embedder.id().to_string();
+
+        for (id, document) in database.documents.iter() {
+            let embeddings = with_embeddings
+                .then(|| database.document_vectors(&[id]))
+                .filter(|vectors| !vectors.is_empty())
+                .map(|vectors| RecordEmbeddings {
+                    model: model.clone(),
+                    sentences: vectors.into_iter().map(|(vector, _)| vector).collect(),
+                });
+
+            let record = DocumentRecord {
+                id,
+                text: document.text.to_string(),
+                metadata: document.metadata.clone(),
+                hash: sha256::digest(&*document.text),
+                embeddings,
+            };
+
+            serde_json::to_writer(&mut writer, &record).unwrap();
+            writer.write_all(b"\n").unwrap();
+        }
+
+        writer.flush().unwrap();
+
+        database.documents.len()
+    }
+
+    /// Adds the documents of a JSONL export, returns the number of documents added
+    ///
+    /// Lines that aren't records and records whose hash doesn't match their text are skipped and reported,
+    /// documents already present are skipped too.
+    /// Embeddings from the same model are used as is, the others are encoded again.
+    pub fn import_jsonl(&self, reader: impl BufRead) -> usize {
+        let mut imported = 0;
+        let mut corrupted = 0;
+        let mut malformed = Vec::new();
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.unwrap();
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record: DocumentRecord = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(error) => {
+                    println!("Line {} skipped: {error}", number + 1);
+                    malformed.push(number + 1);
+
+                    continue;
+                }
+            };
+
+            if sha256::digest(&record.text) != record.hash {
+                corrupted += 1;
+
+                continue;
+            }
+
+            let encoded = record
+                .embeddings
+                .and_then(|embeddings| self.read().reuse_embeddings(&record.text, embeddings));
+
+            let added = match encoded {
+                Some(encoded) => self
+                    .write()
+                    .add_document(record.text, record.metadata, encoded),
+                None => self.add_with_metadata(record.text, record.metadata),
+            };
+
+            if added.is_some() {
+                imported += 1;
+            }
+        }
+
+        if corrupted > 0 {
+            println!("{corrupted} records skipped, their hash doesn't match their text");
+        }
+
+        if !malformed.is_empty() {
+            println!(
+                "{} lines skipped, they aren't documents: {malformed:?}",
+                malformed.len()
+            );
+        }
+
+        imported
+    }
+}
+
+impl VectorDB {
+    /// `None` if the embeddings can't be used by this database, the document is encoded instead
+    fn reuse_embeddings(
+        &self,
+        text: &str,
+        embeddings: RecordEmbeddings,
+    ) -> Option<EncodedDocument> {
+        let sentences = sentences(text)
+            .into_iter()
+            .map(|sentence| sentence.trim_end_matches(END_OF_SENTENCE))
+            .collect::<Vec<_>>();
+
+        // the index asserts that all its vectors have the same dimensions
+        let dimensions = self
+            .dimensions()
+            .or_else(|| Some(embeddings.sentences.first()?.len()));
+
+        if sentences.is_empty()
+            || embeddings.model != self.embedder.id()
+            || embeddings.sentences.len() != sentences.len()
+            || embeddings.sentences.iter().any(|embedding| {
+                Some(embedding.len()) != dimensions
+                    || !embedding.iter().all(|value| value.is_finite())
+            })
+        {
+            return None;
+        }
+
+        let new_embeddings = sentences
+            .iter()
+            .map(|sentence| sha256::digest(*sentence))
+            .zip(embeddings.sentences.iter().cloned())
+            .collect();
+
+        Some(EncodedDocument {
+            embeddings: embeddings.sentences,
+            new_embeddings,
+            entities: self.extractor.extract(text),
+        })
+    }
+}
+
+impl RAG {
+    /// See `Index::export_jsonl`
+    pub fn export_jsonl(&self, writer: impl Write, with_embeddings: bool) -> usize {
+        self.index().export_jsonl(writer, with_embeddings)
+    }
+
+    /// See `Index::import_jsonl`
+    pub fn import_jsonl(&self, reader: impl BufRead) -> usize {
+        self.index().import_jsonl(reader)
+    }
+}
@@ -0,0 +1,5 @@
+/// Lets the RAG ask a LLM for text without depending on the llm crate
+pub trait TextGenerator {
+    /// Completes `prompt` with at most `max_length` tokens
+    fn generate(&self, prompt: &str, max_length: u32) -> String;
+}
@@ -0,0 +1,399 @@
+use crate::{query::STOP_WORDS, sentences, Index, TextGenerator};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+    ops::Range,
+};
+
+/// Query expansion adds at most this many related entities
+const EXPANSION_ENTITIES: usize = 5;
+/// Related entities shown on a card
+const CARD_RELATED: usize = 5;
+/// Words an entity can have, longer lines from an LLM aren't names
+const MAX_ENTITY_WORDS: usize = 5;
+
+const COLORS: &[&str] = &[
+    "red", "blue", "yellow", "white", "black", "green", "purple", "orange",
+];
+
+/// Finds the named things of a document: modules, colors, labels...
+pub trait EntityExtractor {
+    /// Names of the entities in `text`, in any case, a name can be repeated
+    fn extract(&self, text: &str) -> Vec<String>;
+}
+
+/// Capitalized phrases, short uppercase labels and colors
+///
+/// "Simon Says", "FRK" and "red" are entities, the first word of a sentence isn't one on its own.
+#[derive(Default)]
+pub struct RuleExtractor;
+
+impl EntityExtractor for RuleExtractor {
+    fn extract(&self, text: &str) -> Vec<String> {
+        let mut entities = Vec::new();
+
+        for sentence in sentences(text) {
+            let words = sentence
+                .split_whitespace()
+                .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>();
+
+            // positions of the capitalized words being grouped
+            let mut phrase = 0..0;
+
+            for (position, word) in words.iter().enumerate() {
+                let lowercase = word.to_lowercase();
+
+                let is_label = (2..=5).contains(&word.len())
+                    && word.chars().any(|c| c.is_ascii_uppercase())
+                    && word
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+                // "The" starting a sentence isn't part of a name
+                let is_capitalized = !is_label
+                    && word.chars().next().is_some_and(char::is_uppercase)
+                    && !(position == 0 && STOP_WORDS.contains(&lowercase.as_str()));
+
+                if is_capitalized {
+                    if phrase.is_empty() {
+                        phrase = position..position;
+                    }
+
+                    phrase.end = position + 1;
+                } else {
+                    push_phrase(&mut entities, &words, &mut phrase);
+                }
+
+                if is_label {
+                    entities.push(word.to_string());
+                } else if COLORS.contains(&lowercase.as_str()) {
+                    entities.push(lowercase);
+                }
+            }
+
+            push_phrase(&mut entities, &words, &mut phrase);
+        }
+
+        entities
+    }
+}
+
+/// A single capitalized word starting a sentence is only an entity if it's the whole sentence, e.g. a title
+fn push_phrase(entities: &mut Vec<String>, words: &[&str], phrase: &mut Range<usize>) {
+    if phrase.len() > 1 || (phrase.len() == 1 && (phrase.start > 0 || words.len() == 1)) {
+        entities.push(words[phrase.clone()].join(" "));
+    }
+
+    *phrase = 0..0;
+}
+
+/// Asks the LLM for the entities of each document, slower but it finds things like "serial number"
+pub struct GeneratorExtractor<'a>(pub &'a dyn TextGenerator);
+
+impl EntityExtractor for GeneratorExtractor<'_> {
+    fn extract(&self, text: &str) -> Vec<String> {
+        let prompt = format!(
+            "List the named things in the passage below: modules, parts, colors, labels, rules. \
+            One per line, only reply with the list.\n\n\
+            {text}"
+        );
+
+        self.0
+            .generate(&prompt, 100)
+            .lines()
+            .map(|line| {
+                line.trim_start_matches(|c: char| {
+                    c.is_ascii_digit() || c.is_whitespace() || "-*•.)".contains(c)
+                })
+                .trim()
+                .trim_matches('"')
+            })
+            .filter(|line| !line.is_empty() && line.split_whitespace().count() <= MAX_ENTITY_WORDS)
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entity {
+    /// As first found in the documents
+    name: String,
+    documents: BTreeSet<usize>,
+    /// Number of sentences mentioning both entities, by lowercase name
+    related: HashMap<String, u32>,
+}
+
+/// Entities of the documents and how often they're mentioned together, saved with the database
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct EntityGraph {
+    /// By lowercase name
+    entities: HashMap<String, Entity>,
+}
+
+impl EntityGraph {
+    /// Links the entities `names` found in the same sentences of `text`
+    pub(crate) fn add(&mut self, document: usize, text: &str, names: &[String]) {
+        let mut names = names
+            .iter()
+            .map(|name| (name.to_lowercase(), name))
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup_by(|(key1, _), (key2, _)| key1 == key2);
+
+        for (key, name) in &names {
+            self.entities
+                .entry(key.clone())
+                .or_insert_with(|| Entity {
+                    name: name.to_string(),
+                    documents: BTreeSet::new(),
+                    related: HashMap::new(),
+                })
+                .documents
+                .insert(document);
+        }
+
+        let keys = names
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+
+        for (key, other) in co_mentions(text, &keys) {
+            let entity = self.entities.get_mut(key).unwrap();
+
+            *entity.related.entry(other.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Takes back what `add` counted for `document` of `text`, entities only found in it are dropped
+    pub(crate) fn remove(&mut self, document: usize, text: &str) {
+        let keys = self
+            .entities
+            .iter()
+            .filter(|(_, entity)| entity.documents.contains(&document))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        let keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
+
+        for (key, other) in co_mentions(text, &keys) {
+            let related = &mut self.entities.get_mut(key).unwrap().related;
+
+            if let Some(count) = related.get_mut(other) {
+                *count = count.saturating_sub(1);
+
+                if *count == 0 {
+                    related.remove(other);
+                }
+            }
+        }
+
+        for entity in self.entities.values_mut() {
+            entity.documents.remove(&document);
+        }
+
+        self.drop_unmentioned();
+    }
+
+    /// Follows the documents to their new index after a compaction
+    pub(crate) fn renumber(&mut self, mapping: &HashMap<usize, usize>) {
+        for entity in self.entities.values_mut() {
+            entity.documents = entity
+                .documents
+                .iter()
+                .filter_map(|document| mapping.get(document).copied())
+                .collect();
+        }
+
+        self.drop_unmentioned();
+    }
+
+    /// Drops the entities without documents and the relations to them
+    fn drop_unmentioned(&mut self) {
+        self.entities
+            .retain(|_, entity| !entity.documents.is_empty());
+
+        let keys = self.entities.keys().cloned().collect::<BTreeSet<_>>();
+        for entity in self.entities.values_mut() {
+            entity.related.retain(|key, _| keys.contains(key));
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    /// Lowercase names of the entities mentioned in `query`
+    fn entities_in(&self, query: &str) -> Vec<&String> {
+        let query = query.to_lowercase();
+
+        let mut keys = self
+            .entities
+            .keys()
+            .filter(|key| contains_phrase(&query, key))
+            .collect::<Vec<_>>();
+        keys.sort();
+
+        keys
+    }
+
+    /// Entities most often mentioned with the ones of `query`, and not in it
+    fn neighbours(&self, query: &str, count: usize) -> Vec<&str> {
+        let in_query = self.entities_in(query);
+
+        let mut weights: HashMap<&String, u32> = HashMap::new();
+        for key in &in_query {
+            for (related, weight) in &self.entities[*key].related {
+                if !in_query.contains(&related) {
+                    *weights.entry(related).or_default() += weight;
+                }
+            }
+        }
+
+        let mut neighbours = weights.into_iter().collect::<Vec<_>>();
+        neighbours
+            .sort_by(|(key1, weight1), (key2, weight2)| weight2.cmp(weight1).then(key1.cmp(key2)));
+
+        neighbours
+            .into_iter()
+            .take(count)
+            .map(|(key, _)| self.entities[key].name.as_str())
+            .collect()
+    }
+
+    /// `query` followed by the names of its neighbours, for the BM25 search
+    pub(crate) fn expand_query(&self, query: &str) -> String {
+        let neighbours = self.neighbours(query, EXPANSION_ENTITIES);
+
+        if neighbours.is_empty() {
+            query.to_string()
+        } else {
+            format!("{query} {}", neighbours.join(" "))
+        }
+    }
+
+    pub(crate) fn cards(&self, query: &str) -> Vec<EntityCard> {
+        self.entities_in(query)
+            .into_iter()
+            .map(|key| {
+                let entity = &self.entities[key];
+
+                let mut related = entity.related.iter().collect::<Vec<_>>();
+                related.sort_by(|(key1, weight1), (key2, weight2)| {
+                    weight2.cmp(weight1).then(key1.cmp(key2))
+                });
+
+                EntityCard {
+                    name: entity.name.clone(),
+                    documents: entity.documents.iter().copied().collect(),
+                    related: related
+                        .into_iter()
+                        .take(CARD_RELATED)
+                        .map(|(key, _)| self.entities[key].name.clone())
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// (entity, other entity) for each sentence of `text` mentioning both, in both orders
+///
+/// `keys` are lowercase names without duplicates.
+fn co_mentions<'a>(text: &str, keys: &[&'a str]) -> Vec<(&'a str, &'a str)> {
+    let mut pairs = Vec::new();
+
+    for sentence in sentences(text) {
+        let sentence = sentence.to_lowercase();
+
+        let mentioned = keys
+            .iter()
+            .filter(|key| contains_phrase(&sentence, key))
+            .collect::<Vec<_>>();
+
+        for key in &mentioned {
+            for other in &mentioned {
+                if other != key {
+                    pairs.push((**key, **other));
+                }
+            }
+        }
+    }
+
+    pairs
+}
+
+/// `phrase` is in `text` as whole words, both lowercase
+fn contains_phrase(text: &str, phrase: &str) -> bool {
+    text.match_indices(phrase).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + phrase.len()..].chars().next();
+
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// What the database knows about an entity of the query, shown next to the passages
+#[derive(Debug, Serialize)]
+pub struct EntityCard {
+    pub name: String,
+    /// Documents mentioning it
+    pub documents: Vec<usize>,
+    /// Entities most often mentioned with it
+    pub related: Vec<String>,
+}
+
+impl Display for EntityCard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: in {} documents", self.name, self.documents.len())?;
+
+        if !self.related.is_empty() {
+            write!(f, ", related to {}", self.related.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Index {
+    /// Extractor used on the documents added from now on, `RuleExtractor` by default
+    pub fn set_entity_extractor(&self, extractor: Box<dyn EntityExtractor + Send + Sync>) {
+        self.write().extractor = extractor;
+    }
+
+    /// Extracts the entities of every document again with `extractor`, returns the number of entities
+    ///
+    /// Needed after changing the extractor, or to use one that can't be kept like `GeneratorExtractor`.
+    pub fn rebuild_graph(&self, extractor: &dyn EntityExtractor) -> usize {
+        let documents = self
+            .read()
+            .documents
+            .iter()
+            .map(|(index, document)| (index, document.text.to_string()))
+            .collect::<Vec<_>>();
+
+        // extraction can be slow, searches keep running meanwhile
+        let mut graph = EntityGraph::default();
+        for (index, text) in &documents {
+            graph.add(*index, text, &extractor.extract(text));
+        }
+
+        let mut database = self.write();
+
+        // documents removed meanwhile
+        for (index, text) in &documents {
+            if !database.documents.contains(*index) {
+                graph.remove(*index, text);
+            }
+        }
+
+        *database.graph = graph;
+
+        database.graph.len()
+    }
+
+    /// Cards of the entities mentioned in `query`
+    pub fn entity_cards(&self, query: &str) -> Vec<EntityCard> {
+        self.read().graph.cards(query)
+    }
+}
@@ -0,0 +1,350 @@
+use crate::{sentences, BertEmbeddings, Metadata, RAG};
+use serde::Serialize;
+use std::{collections::HashSet, fmt::Display};
+
+/// Put before the numbered passages of a grounded context
+pub(crate) const CITATION_INSTRUCTION: &str = "The passages below are numbered. \
+    After each statement taken from them, cite the passages it comes from like [1] or [2][3].";
+/// A cited passage supports a claim if one of its sentences is this close to it
+const SUPPORT_DISTANCE: f32 = 0.4;
+/// or if it contains this fraction of the terms of the claim
+const SUPPORT_OVERLAP: f32 = 0.6;
+/// Sentences without citation and shorter than this aren't claims, e.g. "Sure!"
+const MIN_CLAIM_WORDS: usize = 4;
+
+/// A numbered passage of the context
+#[derive(Clone, Debug, Serialize)]
+pub struct Source {
+    /// `n` of the `[n]` markers, starts at 1
+    pub number: usize,
+    /// Index of the document in the database
+    pub document: usize,
+    pub metadata: Metadata,
+    /// As shown in the context, compressed or a parent section
+    pub text: String,
+}
+
+/// A sentence of the reply and the passages it cites
+#[derive(Debug, Serialize)]
+pub struct Claim {
+    /// Without its markers
+    pub text: String,
+    pub citations: Vec<Citation>,
+    /// At least one of the citations supports it
+    pub supported: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Citation {
+    pub number: usize,
+    /// `None` if there is no passage with this number in the context
+    pub source: Option<Source>,
+    /// Embeddings distance between the claim and the closest sentence of the passage
+    pub distance: f32,
+    /// Fraction of the terms of the claim found in the passage
+    pub overlap: f32,
+    pub supported: bool,
+}
+
+/// A reply checked against the passages of the context, see `RAG::ground`
+#[derive(Debug, Serialize)]
+pub struct GroundedAnswer {
+    /// As generated, with its `[n]` markers
+    pub text: String,
+    pub claims: Vec<Claim>,
+}
+
+impl GroundedAnswer {
+    /// The reply without its markers, e.g. to be read aloud
+    pub fn plain_text(&self) -> String {
+        split_markers(&self.text).0
+    }
+
+    /// Claims without a citation or whose citations don't support them
+    pub fn unsupported(&self) -> impl Iterator<Item = &Claim> {
+        self.claims.iter().filter(|claim| !claim.supported)
+    }
+}
+
+impl Display for GroundedAnswer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)?;
+
+        let mut cited = self
+            .claims
+            .iter()
+            .flat_map(|claim| &claim.citations)
+            .filter_map(|citation| citation.source.as_ref())
+            .map(|source| (source.number, source.metadata.label()))
+            .collect::<Vec<_>>();
+        cited.sort();
+        cited.dedup();
+
+        if !cited.is_empty() {
+            write!(f, "\n\nSources:")?;
+
+            for (number, label) in cited {
+                write!(f, "\n[{number}] {label}")?;
+            }
+        }
+
+        let mut unsupported = self.unsupported().peekable();
+        if unsupported.peek().is_some() {
+            write!(f, "\n\nUnsupported:")?;
+
+            for claim in unsupported {
+                write!(f, "\n- {}", claim.text)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `[2]` or `[1, 3]` at the start of `text`, returns the numbers and the text after them
+fn marker(text: &str) -> Option<(Vec<usize>, &str)> {
+    let (numbers, rest) = text.strip_prefix('[')?.split_once(']')?;
+
+    let numbers = numbers
+        .split(',')
+        .map(|number| number.trim().parse().ok())
+        .collect::<Option<Vec<usize>>>()?;
+
+    Some((numbers, rest))
+}
+
+/// Removes the `[n]` markers of `text` and the spaces before them, returns the text left and the numbers
+fn split_markers(text: &str) -> (String, Vec<usize>) {
+    let mut plain = String::new();
+    let mut numbers = Vec::new();
+
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        match marker(&rest[start..]) {
+            Some((marker_numbers, after)) => {
+                plain.push_str(rest[..start].trim_end());
+                numbers.extend(marker_numbers);
+                rest = after;
+            }
+            None => {
+                plain.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    plain.push_str(rest);
+
+    (plain, numbers)
+}
+
+impl RAG {
+    /// Numbers the passages of the context and asks the LLM to cite them with `[n]` markers, see `RAG::ground`
+    pub fn set_grounded(&mut self, enabled: bool) {
+        self.grounded = enabled;
+        self.sources.clear();
+    }
+
+    /// Numbered passages of the last context, empty unless grounded
+    pub fn sources(&self) -> &[Source] {
+        &self.sources
+    }
+
+    /// Checks each `[n]` marker of `reply` against the passage it cites, by embeddings distance or shared terms
+    ///
+    /// Claims without a marker or only citing passages that don't support them are flagged as unsupported.
+    pub fn ground(&self, reply: &str) -> GroundedAnswer {
+        // (claim, cited passages)
+        let mut sentences_numbers: Vec<(String, Vec<usize>)> = Vec::new();
+
+        for sentence in sentences(reply) {
+            // "Cut the red wire. [1]" puts the marker at the start of the next sentence
+            let mut rest = sentence.trim_start();
+            let mut leading = Vec::new();
+            while let Some((numbers, after)) = marker(rest) {
+                leading.extend(numbers);
+                rest = after.trim_start();
+            }
+
+            let (text, mut numbers) = split_markers(rest);
+            let text = text.trim();
+
+            match sentences_numbers.last_mut() {
+                Some((_, previous)) => previous.extend(leading),
+                None => numbers.extend(leading),
+            }
+
+            if text.chars().any(char::is_alphanumeric) {
+                sentences_numbers.push((text.to_string(), numbers));
+            }
+        }
+
+        sentences_numbers.retain(|(text, numbers)| {
+            !numbers.is_empty() || text.split_whitespace().count() >= MIN_CLAIM_WORDS
+        });
+
+        // encoding is slow, it's done before locking the index so other conversations can add or search meanwhile
+        let texts = sentences_numbers
+            .iter()
+            .map(|(text, _)| text.as_str())
+            .collect::<Vec<_>>();
+
+        // models can't encode nothing, e.g. "Sure!" isn't a claim
+        if texts.is_empty() {
+            return GroundedAnswer {
+                text: reply.to_string(),
+                claims: Vec::new(),
+            };
+        }
+
+        let embeddings = self.index.embedder().encode(&texts);
+
+        let database = self.index.read();
+
+        let claims = sentences_numbers
+            .into_iter()
+            .zip(embeddings)
+            .map(|((text, mut numbers), embedding)| {
+                numbers.sort_unstable();
+                numbers.dedup();
+
+                let query = BertEmbeddings(embedding);
+
+                let citations = numbers
+                    .into_iter()
+                    .map(|number| {
+                        let Some(source) =
+                            self.sources.iter().find(|source| source.number == number)
+                        else {
+                            return Citation {
+                                number,
+                                source: None,
+                                distance: f32::MAX,
+                                overlap: 0.0,
+                                supported: false,
+                            };
+                        };
+
+                        let distance = database
+                            .sentence_distances(source.document, &query)
+                            .into_iter()
+                            .fold(f32::MAX, f32::min);
+
+                        let language = database
+                            .documents
+                            .get(source.document)
+                            .map(|document| document.language)
+                            .unwrap_or_default();
+                        let claim_terms =
+                            language.analyze(&text).into_iter().collect::<HashSet<_>>();
+                        let source_terms = language
+                            .analyze(&source.text)
+                            .into_iter()
+                            .collect::<HashSet<_>>();
+                        let overlap = if claim_terms.is_empty() {
+                            0.0
+                        } else {
+                            claim_terms.intersection(&source_terms).count() as f32
+                                / claim_terms.len() as f32
+                        };
+
+                        Citation {
+                            number,
+                            source: Some(source.clone()),
+                            distance,
+                            overlap,
+                            supported: distance <= SUPPORT_DISTANCE || overlap >= SUPPORT_OVERLAP,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                Claim {
+                    supported: citations.iter().any(|citation| citation.supported),
+                    text,
+                    citations,
+                }
+            })
+            .collect();
+
+        GroundedAnswer {
+            text: reply.to_string(),
+            claims,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Embedder, HashEmbedder};
+
+    /// Fails like rust-bert does when there is nothing to encode
+    struct StrictEmbedder(HashEmbedder);
+
+    impl Embedder for StrictEmbedder {
+        fn id(&self) -> &str {
+            self.0.id()
+        }
+
+        fn encode(&self, sentences: &[&str]) -> Vec<Vec<f32>> {
+            assert!(!sentences.is_empty());
+
+            self.0.encode(sentences)
+        }
+    }
+
+    #[test]
+    fn markers_are_read_at_the_start_of_the_text() {
+        assert_eq!(marker("[2] Cut it."), Some((vec![2], " Cut it.")));
+        assert_eq!(marker("[1, 3]"), Some((vec![1, 3], "")));
+        assert_eq!(marker("[1,3][4]"), Some((vec![1, 3], "[4]")));
+
+        assert_eq!(marker("Cut it [2]."), None);
+        assert_eq!(marker("[red] wire"), None);
+        assert_eq!(marker("[2"), None);
+        assert_eq!(marker("[]"), None);
+        assert_eq!(marker("[-1]"), None);
+    }
+
+    #[test]
+    fn markers_are_split_from_the_text() {
+        assert_eq!(
+            split_markers("Cut the red wire [1][3]. Then press the button [2, 4]."),
+            (
+                "Cut the red wire. Then press the button.".to_string(),
+                vec![1, 3, 2, 4]
+            )
+        );
+
+        // brackets that aren't markers are kept
+        assert_eq!(
+            split_markers("Press [hold] then release [2]"),
+            ("Press [hold] then release".to_string(), vec![2])
+        );
+        assert_eq!(
+            split_markers("Unclosed [1 marker"),
+            ("Unclosed [1 marker".to_string(), vec![])
+        );
+        assert_eq!(split_markers(""), (String::new(), vec![]));
+
+        // only markers, e.g. the start of the sentence after the one they cite
+        assert_eq!(split_markers("[1] [2]"), (String::new(), vec![1, 2]));
+    }
+
+    #[test]
+    fn replies_without_claims_are_not_encoded() {
+        let rag = RAG::in_memory(Box::new(StrictEmbedder(HashEmbedder::default())));
+
+        for reply in ["Sure!", "OK.", "", "Got it. Next one?"] {
+            let answer = rag.ground(reply);
+
+            assert_eq!(answer.text, reply);
+            assert!(answer.claims.is_empty());
+        }
+
+        // a marker makes a claim whatever its length
+        let answer = rag.ground("Cut it [1].");
+        assert_eq!(answer.claims.len(), 1);
+        assert!(!answer.claims[0].supported);
+        assert!(answer.claims[0].citations[0].source.is_none());
+    }
+}
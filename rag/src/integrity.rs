@@ -0,0 +1,255 @@
+use crate::{segment, Index, VectorDB, RAG};
+use fst::Streamer;
+use serde::Serialize;
+use slab::Slab;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+};
+
+/// Problems found by `Index::verify`
+#[derive(Debug, Default, Serialize)]
+pub struct Integrity {
+    /// Vectors pointing to a document that doesn't exist, `compact` drops them
+    pub orphan_vectors: usize,
+    /// Documents no vector points to, only BM25 can find them
+    pub documents_without_vectors: Vec<usize>,
+    /// Words whose count in the whole database isn't the sum of their count in each document
+    pub word_total_mismatches: usize,
+    /// Documents whose number of words isn't the sum of their word counts
+    pub document_word_mismatches: Vec<usize>,
+    /// Documents whose text doesn't match their hash, or without one
+    pub hash_mismatches: Vec<usize>,
+    /// Hashes of documents that don't exist anymore, they prevent adding the document again
+    pub stale_hashes: usize,
+}
+
+impl Integrity {
+    pub fn is_ok(&self) -> bool {
+        self.orphan_vectors == 0
+            && self.documents_without_vectors.is_empty()
+            && self.word_total_mismatches == 0
+            && self.document_word_mismatches.is_empty()
+            && self.hash_mismatches.is_empty()
+            && self.stale_hashes == 0
+    }
+}
+
+impl Display for Integrity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_ok() {
+            return write!(f, "database ok");
+        }
+
+        writeln!(f, "orphan vectors: {}", self.orphan_vectors)?;
+        writeln!(
+            f,
+            "documents without vectors: {:?}",
+            self.documents_without_vectors
+        )?;
+        writeln!(f, "word total mismatches: {}", self.word_total_mismatches)?;
+        writeln!(
+            f,
+            "document word count mismatches: {:?}",
+            self.document_word_mismatches
+        )?;
+        writeln!(f, "hash mismatches: {:?}", self.hash_mismatches)?;
+        write!(f, "stale hashes: {}", self.stale_hashes)
+    }
+}
+
+impl VectorDB {
+    fn verify(&self) -> Integrity {
+        let mut integrity = Integrity::default();
+
+        let mut with_vectors = BTreeSet::new();
+        for key in self.map.keys() {
+            if self.documents.contains(key.document) {
+                with_vectors.insert(key.document);
+            } else {
+                integrity.orphan_vectors += 1;
+            }
+        }
+
+        let mut word_totals: BTreeMap<Vec<u8>, u64> = BTreeMap::new();
+
+        for (index, document) in self.documents.iter() {
+            if !with_vectors.contains(&index) {
+                integrity.documents_without_vectors.push(index);
+            }
+
+            let mut word_count = 0;
+            let mut stream = document.individual_word_count.0.stream();
+            while let Some((word, count)) = stream.next() {
+                *word_totals.entry(word.to_vec()).or_default() += count;
+                word_count += count;
+            }
+
+            if word_count != document.word_count {
+                integrity.document_word_mismatches.push(index);
+            }
+
+            let hash = sha256::digest(&*document.text);
+            if self.file_hashes.get(&hash) != Some(&index) {
+                integrity.hash_mismatches.push(index);
+            }
+        }
+
+        let saved_totals = self.word_counts();
+        let words = word_totals.keys().chain(saved_totals.keys());
+        integrity.word_total_mismatches = words
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter(|word| word_totals.get(*word) != saved_totals.get(*word))
+            .count();
+
+        integrity.stale_hashes = self
+            .file_hashes
+            .values()
+            .filter(|index| !self.documents.contains(**index))
+            .count();
+
+        integrity
+    }
+
+    /// Gives the documents consecutive indices, returns the new index of each document by its old one
+    ///
+    /// The vector index keeps its graph, `quantize` rebuilds it.
+    fn renumber(&mut self) -> HashMap<usize, usize> {
+        let mapping = self
+            .documents
+            .iter()
+            .enumerate()
+            .map(|(new, (old, _))| (old, new))
+            .collect::<HashMap<_, _>>();
+
+        let documents = std::mem::take(&mut *self.documents);
+        *self.documents = Slab::with_capacity(documents.len());
+        for (_, mut document) in documents {
+            document.metadata.children = document
+                .metadata
+                .children
+                .iter()
+                .filter_map(|child| mapping.get(child).copied())
+                .collect();
+
+            self.documents.insert(document);
+        }
+
+        *self.file_hashes = std::mem::take(&mut *self.file_hashes)
+            .into_iter()
+            .filter_map(|(hash, index)| Some((hash, *mapping.get(&index)?)))
+            .collect();
+
+        self.graph.renumber(&mapping);
+
+        let mut orphans = false;
+        for key in self.map.keys_mut() {
+            match mapping.get(&key.document) {
+                Some(document) => key.document = *document,
+                None => {
+                    key.document = usize::MAX;
+                    orphans = true;
+                }
+            }
+        }
+
+        if orphans {
+            self.map.retain(|key| key.document != usize::MAX);
+        }
+
+        mapping
+    }
+}
+
+impl Index {
+    /// Checks that vectors, word counts and hashes agree with the documents
+    pub fn verify(&self) -> Integrity {
+        self.read().verify()
+    }
+
+    /// Renumbers the documents without the holes left by removals, rebuilds the vector index
+    /// and the rescoring vectors, prunes the embedding cache and saves
+    ///
+    /// Returns the new index of each document by its old one. Conversations over the index keep
+    /// the old indices in their context, `RAG::compact` updates its own.
+    pub fn compact(&self) -> HashMap<usize, usize> {
+        let mapping = {
+            let mut database = self.write();
+
+            let mapping = database.renumber();
+
+            let quantization = database.map.quantization();
+            let rescore = database.full_vectors.is_some();
+
+            database.quantize(quantization, rescore);
+
+            mapping
+        };
+
+        let pruned = self.prune_embedding_cache();
+
+        self.save();
+
+        println!("Database compacted, {pruned} cached embeddings pruned");
+
+        mapping
+    }
+
+    /// Number of previous saves kept, see `Index::restore`
+    pub fn set_backups(&self, backups: usize) {
+        self.write().backups = backups;
+    }
+
+    /// Generations of the saves that can be restored, oldest first
+    pub fn backups(&self) -> Vec<u64> {
+        match &self.paths {
+            Some(paths) if paths.database.exists() => segment::backup_generations(&paths.database),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Goes back to a previous save, returns `false` if there's no backup of `generation`
+    ///
+    /// Changes since the last save are lost, the current save becomes a backup.
+    pub fn restore(&self, generation: u64) -> bool {
+        let Some(paths) = &self.paths else {
+            return false;
+        };
+
+        self.write().restore(&paths.database, generation)
+    }
+}
+
+impl RAG {
+    /// See `Index::verify`
+    pub fn verify(&self) -> Integrity {
+        self.index.verify()
+    }
+
+    /// See `Index::compact`, the documents in the context of this conversation follow their new index
+    pub fn compact(&mut self) {
+        let mapping = self.index.compact();
+
+        self.current_context.retain_mut(|candidate| {
+            let Some(index) = mapping.get(&candidate.index) else {
+                return false;
+            };
+
+            candidate.index = *index;
+
+            true
+        });
+
+        self.pinned = self
+            .pinned
+            .iter()
+            .filter_map(|index| mapping.get(index).copied())
+            .collect();
+
+        self.compressed = std::mem::take(&mut self.compressed)
+            .into_iter()
+            .filter_map(|(index, text)| Some((*mapping.get(&index)?, text)))
+            .collect();
+    }
+}
@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use shared::{END_OF_SENTENCE, SPLIT_WORD};
+
+/// Words looked at to detect the language of a document
+const DETECTION_WORDS: usize = 200;
+/// Stemming never leaves less than this many characters
+const MIN_STEM: usize = 3;
+
+/// Language of a document or a query, it decides how BM25 splits, filters and stems words
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Language {
+    #[default]
+    English,
+    French,
+    German,
+    Spanish,
+    Italian,
+    Portuguese,
+    Dutch,
+}
+
+const LANGUAGES: [Language; 7] = [
+    Language::English,
+    Language::French,
+    Language::German,
+    Language::Spanish,
+    Language::Italian,
+    Language::Portuguese,
+    Language::Dutch,
+];
+
+impl Language {
+    /// Language whose most common words are the most used in `text`, `None` if no language stands out
+    ///
+    /// Short queries like "Simon Says colors" often don't have any.
+    pub fn detect(text: &str) -> Option<Language> {
+        let words = text
+            .split(|c| END_OF_SENTENCE.contains(&c) || SPLIT_WORD.contains(&c))
+            .map(|word| {
+                word.trim_matches(|c: char| !c.is_alphanumeric())
+                    .to_lowercase()
+            })
+            .filter(|word| !word.is_empty())
+            .take(DETECTION_WORDS)
+            .collect::<Vec<_>>();
+
+        let mut scores = LANGUAGES
+            .iter()
+            .map(|language| {
+                let hits = words
+                    .iter()
+                    .filter(|word| language.stop_words().contains(&word.as_str()))
+                    .count();
+
+                (*language, hits)
+            })
+            .collect::<Vec<_>>();
+        scores.sort_by(|(_, hits1), (_, hits2)| hits2.cmp(hits1));
+
+        match scores.as_slice() {
+            [(language, best), (_, second), ..] if *best > 0 && best > second => Some(*language),
+            _ => None,
+        }
+    }
+
+    fn stop_words(self) -> &'static [&'static str] {
+        match self {
+            Language::English => &[
+                "the", "and", "is", "are", "of", "to", "in", "it", "you", "that", "what", "how",
+                "with", "for", "this", "if", "do", "does", "which", "there",
+            ],
+            Language::French => &[
+                "le", "la", "les", "et", "est", "sont", "un", "une", "des", "du", "que", "qui",
+                "pour", "dans", "pas", "je", "vous", "il", "ce", "comment", "quel", "quelle",
+            ],
+            Language::German => &[
+                "der", "die", "das", "und", "ist", "sind", "nicht", "ein", "eine", "ich", "sie",
+                "es", "mit", "zu", "den", "wie", "was", "welche", "wenn", "auf",
+            ],
+            Language::Spanish => &[
+                "el", "los", "las", "y", "es", "son", "una", "que", "por", "para", "con", "cómo",
+                "qué", "del", "cuál", "si", "hay", "lo",
+            ],
+            Language::Italian => &[
+                "il", "lo", "gli", "è", "sono", "uno", "che", "di", "per", "con", "non", "come",
+                "cosa", "della", "quale", "se", "nel",
+            ],
+            Language::Portuguese => &[
+                "o", "os", "as", "é", "são", "um", "uma", "que", "em", "para", "com", "não",
+                "como", "você", "qual", "se", "do", "da",
+            ],
+            Language::Dutch => &[
+                "de", "het", "een", "en", "is", "zijn", "van", "niet", "ik", "je", "dat", "wat",
+                "hoe", "met", "voor", "welke", "als",
+            ],
+        }
+    }
+
+    /// Longest first
+    fn suffixes(self) -> &'static [&'static str] {
+        match self {
+            Language::English => &["ing", "ed", "s"],
+            Language::French => &["ements", "ement", "ées", "ée", "és", "é", "es", "s", "x"],
+            Language::German => &["ungen", "ung", "en", "er", "es", "e", "n", "s"],
+            Language::Spanish => &["mente", "ciones", "ción", "es", "s"],
+            Language::Italian => &["mente", "zioni", "zione", "i", "e"],
+            Language::Portuguese => &["mente", "ções", "ção", "es", "s"],
+            Language::Dutch => &["heden", "heid", "en", "s"],
+        }
+    }
+
+    fn stem(self, word: &str) -> String {
+        for suffix in self.suffixes() {
+            if let Some(stem) = word.strip_suffix(suffix) {
+                if stem.chars().count() >= MIN_STEM {
+                    return stem.to_string();
+                }
+            }
+        }
+
+        word.to_string()
+    }
+
+    /// Terms BM25 counts in `text`: lowercase, without the most common words and stemmed
+    pub(crate) fn analyze(self, text: &str) -> Vec<String> {
+        text.split(|c| END_OF_SENTENCE.contains(&c) || SPLIT_WORD.contains(&c))
+            .map(|word| {
+                word.trim_matches(|c: char| !c.is_alphanumeric())
+                    .to_lowercase()
+            })
+            .filter(|word| !word.is_empty() && !self.stop_words().contains(&word.as_str()))
+            .map(|word| self.stem(&word))
+            .collect()
+    }
+}
@@ -1,24 +1,67 @@
+mod cache;
+mod compression;
+mod context;
+mod embedder;
+pub mod eval;
+mod explain;
+mod export;
+mod generator;
+mod graph;
+mod grounding;
+mod integrity;
+mod language;
+mod memory;
+mod mmr;
+mod parent;
+mod policy;
+mod quantization;
+mod query;
+mod segment;
+pub mod server;
+mod summary;
+mod table;
 mod website;
 mod wiki_dump;
 
+pub use embedder::{BertEmbedder, Embedder, HashEmbedder};
+pub use explain::{Explain, Explanation, TermScore, Verdict};
+pub use export::{DocumentRecord, RecordEmbeddings};
+pub use generator::TextGenerator;
+pub use graph::{EntityCard, EntityExtractor, GeneratorExtractor, RuleExtractor};
+pub use grounding::{Citation, Claim, GroundedAnswer, Source};
+pub use integrity::Integrity;
+pub use language::Language;
+pub use memory::{Memory, Recollection, MEMORY_SOURCE};
+pub use policy::{ContextPolicy, ExponentialDecay, LegacyDecay, Pinned, SlidingWindow};
+pub use quantization::Quantization;
+pub use query::{QueryRewriter, RetrievalMode};
+pub use segment::OpenError;
+pub use summary::SUMMARY_SOURCE;
+pub use table::TableRow;
 pub use wiki_dump::parse_wikipedia_dump;
 
+use cache::EmbeddingCache;
+use fst::Streamer;
+use graph::EntityGraph;
+use grounding::CITATION_INSTRUCTION;
 use indicatif::ProgressStyle;
-use instant_distance::{HnswMap, Point, Search};
+use instant_distance::Point;
+use parent::Section;
 use pdfium_render::pdfium::Pdfium;
-use rust_bert::pipelines::sentence_embeddings::{
-    SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
-};
-use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
-use shared::END_OF_SENTENCE;
+use quantization::{FullVectors, VectorIndex};
+use segment::{Bytes, Lazy, Rows, Text};
+use serde::{Deserialize, Serialize};
+use shared::{Speaker, CHARACTERS_PER_TOKEN, END_OF_SENTENCE};
 use slab::Slab;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 use std::io::Write;
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
     ffi::OsStr,
-    fs::{File, OpenOptions},
-    path::Path,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 
 /// With 32k tokens we need to set a limit to the number of characters for the context
@@ -26,38 +69,256 @@ use std::{
 /// For safety we'll only use 5k tokens, or around 20k characters
 const CHARACTERS_PER_CHUNK: usize = 20000;
 const CHUNK_OVERLAP: usize = 10000;
+const DATABASE_PATH: &str = "./resources/database.data";
+const EMBEDDING_CACHE_PATH: &str = "./resources/embeddings.cache";
+/// Number of documents kept in the context
+const CONTEXT_DOCUMENTS: usize = 5;
+/// Search results further than this are never added to the context
+const DISTANCE_THRESHOLD: f32 = 0.3;
+/// With a rescored index, this many times more documents are rescored than returned
+const RESCORING_FACTOR: usize = 4;
+/// Sentences encoded at once, the progress bar moves after each batch
+const ENCODING_BATCH: usize = 32;
+
+/// Documents and their search indices, shared by all the conversations
+///
+/// Searches only take a read lock so they run concurrently,
+/// adding documents encodes them without blocking searches and only locks to insert them.
+/// Encoding the queries still takes turns: `BertEmbedder` holds a single model behind a mutex,
+/// a search with embeddings waits for the ones encoding their query before it.
+pub struct Index {
+    database: RwLock<VectorDB>,
+    /// `None` for a database that is never saved
+    paths: Option<Paths>,
+}
 
-pub struct RAG {
-    database: VectorDB,
-    current_context: Vec<Candidate>,
+// conversations on other threads share the index, e.g. the requests of the server
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<Index>();
+};
+
+struct Paths {
+    database: PathBuf,
+    cache: PathBuf,
 }
 
-impl RAG {
-    pub fn new() -> RAG {
-        let database = File::open("./resources/database.data")
-            .map(|database_file| bincode::deserialize_from(database_file).unwrap())
-            .unwrap_or_else(|_| VectorDB::new());
+impl Index {
+    /// Opens the database saved in `./resources`, its segments are memory-mapped so opening it doesn't depend on its size
+    pub fn open() -> Result<Index, OpenError> {
+        Index::open_paths(Index::default_paths(), None, false)
+    }
 
-        RAG {
-            database,
-            current_context: Vec::new(),
+    /// Opens the database saved in `./resources` with `embedder`, see `Index::open_at_with_embedder`
+    pub fn open_with_embedder(embedder: Box<dyn Embedder>) -> Result<Index, OpenError> {
+        Index::open_paths(Index::default_paths(), Some(embedder.into()), false)
+    }
+
+    /// Encodes the database saved in `./resources` again with `embedder`, see `Index::reencode_at`
+    pub fn reencode(embedder: Box<dyn Embedder>) -> Result<Index, OpenError> {
+        Index::reencode_paths(Index::default_paths(), embedder)
+    }
+
+    /// Opens the database saved at `path`, or creates it on `save`
+    ///
+    /// The embedding cache is kept next to it, e.g. `path.cache`.
+    pub fn open_at(path: impl AsRef<Path>) -> Result<Index, OpenError> {
+        Index::open_paths(Index::paths_at(path.as_ref()), None, false)
+    }
+
+    /// Opens the database saved at `path` with `embedder`, e.g. `BertEmbedder::local`
+    ///
+    /// A new database is encoded with it. Opening a database saved with another model fails with
+    /// `OpenError::ModelMismatch` instead of encoding it again, see `Index::reencode_at`.
+    pub fn open_at_with_embedder(
+        path: impl AsRef<Path>,
+        embedder: Box<dyn Embedder>,
+    ) -> Result<Index, OpenError> {
+        Index::open_paths(Index::paths_at(path.as_ref()), Some(embedder.into()), false)
+    }
+
+    /// Encodes every document of the database saved at `path` with `embedder` and saves it
+    ///
+    /// The cached embeddings of the model are reused, it's only slow the first time.
+    pub fn reencode_at(
+        path: impl AsRef<Path>,
+        embedder: Box<dyn Embedder>,
+    ) -> Result<Index, OpenError> {
+        Index::reencode_paths(Index::paths_at(path.as_ref()), embedder)
+    }
+
+    /// Opens the database saved at `path` with the embedder of another index, see `Memory::open_at`
+    ///
+    /// A database saved with another model is encoded again, it's saved with the new one on the next `save`.
+    pub(crate) fn open_at_sharing(
+        path: impl AsRef<Path>,
+        embedder: Arc<dyn Embedder>,
+    ) -> Result<Index, OpenError> {
+        Index::open_paths(Index::paths_at(path.as_ref()), Some(embedder), true)
+    }
+
+    fn reencode_paths(paths: Paths, embedder: Box<dyn Embedder>) -> Result<Index, OpenError> {
+        let index = Index::open_paths(paths, Some(embedder.into()), true)?;
+
+        index.save();
+
+        Ok(index)
+    }
+
+    fn default_paths() -> Paths {
+        Paths {
+            database: PathBuf::from(DATABASE_PATH),
+            cache: PathBuf::from(EMBEDDING_CACHE_PATH),
+        }
+    }
+
+    fn paths_at(path: &Path) -> Paths {
+        Paths {
+            database: path.to_path_buf(),
+            cache: path.with_extension("cache"),
+        }
+    }
+
+    /// Without `embedder` the database is opened with the model it was saved with
+    ///
+    /// With `reencode` a database saved with another model is encoded again, otherwise it's an error.
+    fn open_paths(
+        paths: Paths,
+        embedder: Option<Arc<dyn Embedder>>,
+        reencode: bool,
+    ) -> Result<Index, OpenError> {
+        let exists = paths.database.exists();
+
+        // databases saved by the first version don't have a model, they are encoded again on migration
+        let saved_model = exists
+            .then(|| segment::saved_model(&paths.database).ok())
+            .flatten();
+
+        let embedder = match (embedder, saved_model) {
+            (Some(embedder), _) => embedder,
+            (None, Some(model)) => match BertEmbedder::from_id(&model) {
+                Some(embedder) => Arc::new(embedder),
+                None => return Err(OpenError::UnknownModel(model)),
+            },
+            (None, None) => default_embedder(),
+        };
+
+        let mut database = VectorDB::new(embedder);
+
+        database.cache = EmbeddingCache::load(&paths.cache);
+
+        if exists {
+            match database.load(&paths.database) {
+                Err(OpenError::UnknownFormat) => {
+                    let texts =
+                        segment::legacy_texts(&paths.database).ok_or(OpenError::UnknownFormat)?;
+
+                    database.migrate(&paths.database, texts);
+                }
+                result => result?,
+            }
+
+            let saved = database.vectors_model.clone().unwrap_or_default();
+
+            if saved != database.embedder.id() {
+                if !reencode {
+                    return Err(OpenError::ModelMismatch {
+                        saved,
+                        embedder: database.embedder.id().to_string(),
+                    });
+                }
+
+                println!(
+                    "Database encoded with {saved}, encoding it again with {}",
+                    database.embedder.id()
+                );
+
+                database.encode_again();
+            }
         }
+
+        Ok(Index {
+            database: RwLock::new(database),
+            paths: Some(paths),
+        })
     }
 
-    pub fn add(&mut self, text: impl Into<String>) {
+    /// Empty database that is never read from or saved to disk
+    pub fn in_memory(embedder: Box<dyn Embedder>) -> Index {
+        Index {
+            database: RwLock::new(VectorDB::new(embedder.into())),
+            paths: None,
+        }
+    }
+
+    /// Embedder encoding the documents, e.g. to open a `Memory` without loading the model twice
+    pub fn embedder(&self) -> Arc<dyn Embedder> {
+        self.read().embedder.clone()
+    }
+
+    /// A conversation or request that panicked holding the lock doesn't stop the others from using the index
+    fn read(&self) -> RwLockReadGuard<'_, VectorDB> {
+        self.database.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, VectorDB> {
+        self.database
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Stores the embedding index with less precision to use less memory, see `Quantization`
+    ///
+    /// With `rescore` the full precision vectors are kept in their own segment and the closest results are re-ranked with them.
+    /// Going back to `Quantization::None` without them doesn't bring the lost precision back.
+    pub fn set_quantization(&self, quantization: Quantization, rescore: bool) {
+        self.write().quantize(quantization, rescore);
+
+        println!(
+            "Embedding index stored as {quantization:?}, vectors use {} KiB",
+            self.vector_memory() / 1024
+        );
+    }
+
+    pub fn quantization(&self) -> Quantization {
+        self.read().map.quantization()
+    }
+
+    /// Bytes used by the vectors of the embedding index, the full precision vectors kept for rescoring aren't counted
+    pub fn vector_memory(&self) -> usize {
+        self.read().map.memory()
+    }
+
+    /// Bytes of the embeddings cached since the last save, the saved cache is memory-mapped
+    pub fn cache_memory(&self) -> usize {
+        self.read().cache.memory()
+    }
+
+    pub fn add(&self, text: impl Into<String>) -> Option<usize> {
+        self.add_with_metadata(text, Metadata::default())
+    }
+
+    /// Returns the index of the new document, `None` if it's empty or already present
+    pub fn add_with_metadata(&self, text: impl Into<String>, metadata: Metadata) -> Option<usize> {
         let text: String = text.into();
 
         if text.is_empty() {
-            return;
+            return None;
         }
 
-        self.database.add_document(text);
+        // encoding is the slow part, it's done without blocking searches
+        let encoded = self.read().encode_document(&text)?;
+
+        self.write().add_document(text, metadata, encoded)
     }
 
-    pub fn add_document(&mut self, path: impl AsRef<Path>) {
+    pub fn add_document(&self, path: impl AsRef<Path>) {
         let path = path.as_ref();
         println!("Extracting text from {:?}", path);
 
+        let source = path.to_string_lossy().to_string();
+
         let extension = path.extension().unwrap_or(OsStr::new(""));
 
         match extension.to_str().unwrap() {
@@ -71,17 +332,18 @@ impl RAG {
                 );
 
                 let folder_name = path.file_stem().unwrap().to_str().unwrap();
-                std::fs::create_dir(format!("./resources/{folder_name}")).unwrap();
+                std::fs::create_dir_all(format!("./resources/{folder_name}")).unwrap();
 
                 let document = pdfium.load_pdf_from_file(path, None).unwrap();
                 for (page_number, page) in document.pages().iter().enumerate() {
                     let page = page.text().unwrap().all();
-                    let page = page
-                        .splitn(5, &[' ', '\n'])
-                        .last()
-                        .unwrap()
-                        .replace("\r\n", "\n")
-                        .to_string();
+                    let page = table::align_tables(
+                        &page
+                            .splitn(5, &[' ', '\n'])
+                            .last()
+                            .unwrap()
+                            .replace("\r\n", "\n"),
+                    );
 
                     let mut file = std::fs::File::create(format!(
                         "./resources/{folder_name}/page {page_number}.md"
@@ -90,7 +352,8 @@ impl RAG {
 
                     file.write_all(page.as_bytes()).unwrap();
 
-                    self.add(page);
+                    // a page is a single chunk
+                    self.add_markdown(&page, &source, usize::MAX, 0, page_number);
                 }
 
                 println!("Done extracting");
@@ -98,114 +361,533 @@ impl RAG {
             "md" => {
                 let content = std::fs::read_to_string(path).unwrap();
 
-                if content.len() <= CHARACTERS_PER_CHUNK {
-                    self.add(content);
-                    return;
-                }
+                self.add_markdown(&content, &source, CHARACTERS_PER_CHUNK, CHUNK_OVERLAP, 0);
+            }
+            _ => println!("Document not supported"),
+        }
+    }
 
-                let mut chunk_start = 0;
-                loop {
-                    if chunk_start + CHARACTERS_PER_CHUNK >= content.len() {
-                        let chunk = &content[chunk_start..];
+    fn search_mode(
+        &self,
+        query: &str,
+        mode: &RetrievalMode,
+        top_k: usize,
+        threshold: f32,
+    ) -> Vec<(usize, f32)> {
+        let queries = mode.expand(query, |query| self.read().graph.expand_query(query));
+
+        if let [(bm25_query, embeddings_query)] = queries.as_slice() {
+            return self.search_threashold(bm25_query, embeddings_query, top_k, threshold);
+        }
 
-                        self.add(chunk);
+        let results = queries
+            .iter()
+            .map(|(bm25_query, embeddings_query)| {
+                self.search_threashold(bm25_query, embeddings_query, top_k, threshold)
+            })
+            .collect();
 
-                        break;
-                    }
+        query::reciprocal_rank_fusion(results, top_k)
+    }
 
-                    let chunk_end = (chunk_start..chunk_start + CHARACTERS_PER_CHUNK)
-                        .rev()
-                        .find(|&i| content.is_char_boundary(i))
-                        .unwrap();
+    /// `embeddings_query` is usually `query` but can be anything that should be close to the answer
+    fn search_threashold(
+        &self,
+        query: &str,
+        embeddings_query: &str,
+        top_k: usize,
+        threshold: f32,
+    ) -> Vec<(usize, f32)> {
+        let database = self.read();
 
-                    let chunk = &content[chunk_start..chunk_end];
+        let embeddings_results = database.search_embeddings(embeddings_query, top_k);
+        let bm25_results = database.bm35_plus(query);
 
-                    self.add(chunk);
+        let mut results = fuse(bm25_results, &embeddings_results);
 
-                    chunk_start = (chunk_start..chunk_end - CHUNK_OVERLAP)
-                        .rev()
-                        .find(|&i| content.is_char_boundary(i))
-                        .unwrap();
-                }
+        results.truncate(top_k);
+
+        for i in (0..results.len()).rev() {
+            if results[i].1 > threshold {
+                results.pop();
+            } else {
+                break;
             }
-            _ => println!("Document not supported"),
         }
+
+        results
     }
 
-    fn search_threashold(&self, query: &str, top_k: usize, threshold: f32) -> Vec<(usize, f32)> {
-        let embeddings_results = self.database.search_embeddings(query, top_k);
-        let mut bm25_results = self.database.bm35_plus(query);
+    /// Documents closest to `query`, the ones further than `max_distance` are left out
+    pub fn search(&self, query: &str, top_k: usize, max_distance: f32) -> Vec<SearchResult> {
+        let results = self.search_threashold(query, query, top_k, max_distance);
 
-        // TODO: use a cross-encoder
-        //  we're currently merging embeddings and bm25 scores
-        //  this is not mathematically correct
+        let database = self.read();
 
-        {
-            if bm25_results.is_empty() {
-                return Vec::new();
-            }
+        results
+            .into_iter()
+            .filter_map(|(id, distance)| {
+                let document = database.documents.get(id)?;
+
+                Some(SearchResult {
+                    id,
+                    distance,
+                    text: document.text.to_string(),
+                    metadata: document.metadata.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Does nothing for an in memory database
+    pub fn save(&self) {
+        let Some(paths) = &self.paths else {
+            return;
+        };
 
-            let last_bm25 = bm25_results.first().unwrap().1;
+        let mut database = self.write();
 
-            for (_, score) in &mut bm25_results {
-                *score /= last_bm25;
-                *score = 1.0 - *score;
-            }
+        database.save(&paths.database);
+
+        database.cache.save(&paths.cache);
+    }
+
+    /// Returns `false` if there's no document at `index`
+    ///
+    /// Its text is only dropped from the disk on the next `save`, and its full precision vectors on `compact`.
+    pub fn remove_document(&self, index: usize) -> bool {
+        self.write().remove_document(index)
+    }
+
+    /// Indices of the documents coming from `source`, e.g. all the pages of a pdf
+    pub fn documents_from(&self, source: &str) -> Vec<usize> {
+        self.read()
+            .documents
+            .iter()
+            .filter(|(_, document)| {
+                document.metadata.source == source
+                    || Path::new(&document.metadata.source).ends_with(source)
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let database = self.read();
+
+        let characters = database
+            .documents
+            .iter()
+            .map(|(_, document)| document.text.len())
+            .sum::<usize>();
+
+        Stats {
+            documents: database.documents.len(),
+            vectors: database.map.len(),
+            vocabulary: database.word_counts().len(),
+            average_words: database.average_word_count,
+            average_characters: characters as f32 / database.documents.len().max(1) as f32,
+            quantization: database.map.quantization(),
+            vector_memory: database.map.memory(),
+            entities: database.graph.len(),
         }
+    }
 
-        let mut results =
-            bm25_results
-                .into_iter()
-                .map(|(bm25_index, mut score)| {
-                    if let Some(embeddings_score) = embeddings_results.iter().find_map(
-                        |&(embeddings_index, embeddings_score)| {
-                            (bm25_index == embeddings_index).then(|| embeddings_score)
-                        },
-                    ) {
-                        score = (score + embeddings_score) / 2.0;
-                    }
+    /// Removes the cached embeddings that aren't used by any document anymore
+    ///
+    /// Returns the number of embeddings removed, `save` writes the pruned cache to disk.
+    pub fn prune_embedding_cache(&self) -> usize {
+        let mut database = self.write();
 
-                    (bm25_index, score)
-                })
+        let referenced = database
+            .documents
+            .iter()
+            .flat_map(|(_, document)| {
+                sentences(&document.text)
+                    .into_iter()
+                    .map(|sentence| sha256::digest(sentence.trim_end_matches(END_OF_SENTENCE)))
+            })
+            .collect::<HashSet<_>>();
+
+        database.cache.prune(&referenced)
+    }
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    /// Index of the document in the database
+    pub id: usize,
+    pub distance: f32,
+    pub text: String,
+    pub metadata: Metadata,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    pub documents: usize,
+    /// One per sentence
+    pub vectors: usize,
+    /// Number of different words
+    pub vocabulary: usize,
+    pub average_words: f32,
+    pub average_characters: f32,
+    pub quantization: Quantization,
+    /// Bytes used by the vectors of the embedding index
+    pub vector_memory: usize,
+    /// Nodes of the entity graph
+    pub entities: usize,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "documents: {}", self.documents)?;
+        writeln!(
+            f,
+            "vectors: {} ({:?}, {} KiB)",
+            self.vectors,
+            self.quantization,
+            self.vector_memory / 1024
+        )?;
+        writeln!(f, "vocabulary: {} words", self.vocabulary)?;
+        writeln!(f, "entities: {}", self.entities)?;
+        write!(
+            f,
+            "average document: {:.0} words, {:.0} characters",
+            self.average_words, self.average_characters
+        )
+    }
+}
+
+/// A conversation over an `Index`, keeps track of the documents in its context
+pub struct RAG {
+    index: Arc<Index>,
+    current_context: Vec<Candidate>,
+    context_policy: Box<dyn ContextPolicy>,
+    /// Documents kept first in the context whatever the policy, see `RAG::pin`
+    pinned: Vec<usize>,
+    mmr_lambda: Option<f32>,
+    context_budget: Option<usize>,
+    compression: Option<f32>,
+    parent_sections: bool,
+    /// Compressed text or parent sections of the documents in the context
+    compressed: HashMap<usize, String>,
+    entity_cards: bool,
+    /// Cards of the entities of the last query, one per line
+    cards: String,
+    memory: Option<Arc<Memory>>,
+    /// Memories recalled for the last query
+    memories: String,
+    grounded: bool,
+    /// Numbered passages of the last context, see `RAG::ground`
+    sources: Vec<Source>,
+}
+
+impl RAG {
+    /// Conversation over the database saved in `./resources`, see `Index::open`
+    pub fn new() -> Result<RAG, OpenError> {
+        Ok(RAG::with_index(Arc::new(Index::open()?)))
+    }
+
+    /// Conversation over an empty database that is never read from or saved to disk
+    pub fn in_memory(embedder: Box<dyn Embedder>) -> RAG {
+        RAG::with_index(Arc::new(Index::in_memory(embedder)))
+    }
+
+    /// New conversation sharing `index` with the others
+    pub fn with_index(index: Arc<Index>) -> RAG {
+        RAG {
+            index,
+            current_context: Vec::new(),
+            context_policy: Box::new(LegacyDecay),
+            pinned: Vec::new(),
+            mmr_lambda: None,
+            context_budget: None,
+            compression: None,
+            parent_sections: false,
+            compressed: HashMap::new(),
+            entity_cards: false,
+            cards: String::new(),
+            memory: None,
+            memories: String::new(),
+            grounded: false,
+            sources: Vec::new(),
+        }
+    }
+
+    /// Documents are added and saved through the index, clone the `Arc` to share it with other conversations
+    pub fn index(&self) -> &Arc<Index> {
+        &self.index
+    }
+
+    /// Changes how documents are kept in the context from one turn to the next
+    pub fn set_context_policy(&mut self, policy: Box<dyn ContextPolicy>) {
+        self.context_policy = policy;
+    }
+
+    /// Keeps the document `index` in the context until it's unpinned, from now on
+    pub fn pin(&mut self, index: usize) {
+        if self.pinned.contains(&index) {
+            return;
+        }
+
+        self.pinned.push(index);
+
+        self.current_context
+            .retain(|candidate| candidate.index != index);
+        self.current_context.insert(
+            (self.pinned.len() - 1).min(self.current_context.len()),
+            Candidate {
+                distance: 0.0,
+                index,
+            },
+        );
+    }
+
+    /// The document leaves the context, it can come back when a query finds it again
+    pub fn unpin(&mut self, index: usize) {
+        if !self.pinned.contains(&index) {
+            return;
+        }
+
+        self.pinned.retain(|&pinned| pinned != index);
+        self.current_context
+            .retain(|candidate| candidate.index != index);
+        self.compressed.remove(&index);
+    }
+
+    pub fn pinned(&self) -> &[usize] {
+        &self.pinned
+    }
+
+    /// Indices of the documents currently in the context, e.g. to pin them
+    pub fn context_documents(&self) -> Vec<usize> {
+        self.current_context
+            .iter()
+            .map(|candidate| candidate.index)
+            .collect()
+    }
+
+    /// Selects the context with Maximal Marginal Relevance instead of only keeping the closest documents
+    ///
+    /// `lambda` goes from 0.0, only diversity, to 1.0, only relevance.
+    /// `None` disables it.
+    pub fn set_mmr_lambda(&mut self, lambda: Option<f32>) {
+        self.mmr_lambda = lambda.map(|lambda| lambda.clamp(0.0, 1.0));
+    }
+
+    /// Limits the context to `tokens`, passages are labeled with their source
+    ///
+    /// `None` puts all passages in the context as is.
+    pub fn set_context_budget(&mut self, tokens: Option<usize>) {
+        self.context_budget = tokens;
+    }
+
+    /// Only keeps the sentences of the context that are close to the query, and their neighbours
+    ///
+    /// `max_distance` is compared to the embeddings distance of each sentence, e.g. 0.6.
+    /// `None` keeps the whole documents.
+    pub fn set_compression(&mut self, max_distance: Option<f32>) {
+        self.compression = max_distance;
+        self.compressed.clear();
+    }
+
+    /// Puts the sections around the sentences closest to the query in the context instead of whole documents
+    ///
+    /// Sections are stored with the documents, see `Index::set_section_size` for their size.
+    /// It takes precedence over compression.
+    pub fn set_parent_sections(&mut self, enabled: bool) {
+        self.parent_sections = enabled;
+        self.compressed.clear();
+    }
+
+    /// Puts a card for each entity of the query before the passages, with the entities related to it
+    pub fn set_entity_cards(&mut self, enabled: bool) {
+        self.entity_cards = enabled;
+        self.cards.clear();
+    }
+
+    fn select_sections(&mut self, query: &str) {
+        let database = self.index.read();
+
+        let query = database.embed(query);
+
+        self.compressed.clear();
+
+        // chunks overlap, the same section can be in several documents of the context
+        let mut seen = HashSet::new();
+        for candidate in &self.current_context {
+            let sections = database
+                .parent_sections(candidate.index, &query)
+                .into_iter()
+                .filter(|section| seen.insert(*section))
                 .collect::<Vec<_>>();
 
-        results.sort_unstable_by(|(_, score1), (_, score2)| score1.partial_cmp(score2).unwrap());
+            self.compressed
+                .insert(candidate.index, sections.join("\n[...]\n"));
+        }
+    }
 
-        results.truncate(top_k);
+    fn compress_context(&mut self, query: &str, max_distance: f32) {
+        let database = self.index.read();
 
-        for i in (0..results.len()).rev() {
-            if results[i].1 > threshold {
-                results.pop();
-            } else {
-                break;
-            }
+        let query = database.embed(query);
+
+        self.compressed.clear();
+
+        let mut original_length = 0;
+        let mut compressed_length = 0;
+        for candidate in &self.current_context {
+            let Some(document) = database.documents.get(candidate.index) else {
+                continue;
+            };
+
+            let text = &document.text;
+            let distances = database.sentence_distances(candidate.index, &query);
+
+            let compressed = compression::compress(&sentences(text), &distances, max_distance);
+
+            original_length += text.len();
+            compressed_length += compressed.len();
+
+            self.compressed.insert(candidate.index, compressed);
         }
 
-        results
+        if original_length > 0 {
+            println!(
+                "Context compressed to {:.0}% ({compressed_length}/{original_length} characters)",
+                compressed_length as f32 / original_length as f32 * 100.0
+            );
+        }
     }
 
-    pub fn save(&self) {
-        let database_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open("./resources/database2.data")
-            .unwrap();
+    /// Rows of tables are shown as a markdown table with their headers
+    fn document_text<'a>(&'a self, document: &'a Document, index: usize) -> Cow<'a, str> {
+        match (self.compressed.get(&index), &document.metadata.table) {
+            (Some(compressed), _) => Cow::Borrowed(compressed),
+            (None, Some(row)) => Cow::Owned(row.to_markdown()),
+            (None, None) => Cow::Borrowed(&document.text),
+        }
+    }
+
+    fn context_to_string(&mut self) -> String {
+        let (context, sources) = self.assemble_context();
 
-        bincode::serialize_into(&database_file, &self.database).unwrap();
-        drop(database_file);
+        self.sources = sources;
 
-        std::fs::rename("./resources/database2.data", "./resources/database.data").unwrap();
+        context
     }
 
-    fn context_to_string(&self) -> String {
-        (&self.current_context)
+    /// The context and, when grounded, its numbered passages
+    fn assemble_context(&self) -> (String, Vec<Source>) {
+        let database = self.index.read();
+
+        // documents can be removed by another conversation sharing the index
+        let documents = self
+            .current_context
             .iter()
-            .map(|candidate| self.database.documents[candidate.index].text.as_str())
+            .filter_map(|candidate| {
+                let document = database.documents.get(candidate.index)?;
+
+                Some((
+                    candidate.index,
+                    document,
+                    self.document_text(document, candidate.index),
+                ))
+            })
+            // all the sections of a document can already be in the context through another one
+            .filter(|(_, _, text)| !text.is_empty())
+            .collect::<Vec<_>>();
+
+        let (passages, order) = if let Some(budget) = self.context_budget {
+            let passages = documents
+                .iter()
+                .map(|(_, document, text)| context::Passage {
+                    text,
+                    metadata: &document.metadata,
+                })
+                .collect::<Vec<_>>();
+
+            // the cards, memories and citation instruction are part of the budget
+            let notes = self.notes().len()
+                + if self.grounded {
+                    CITATION_INSTRUCTION.len()
+                } else {
+                    0
+                };
+            let budget = budget.saturating_sub(notes.div_ceil(CHARACTERS_PER_TOKEN));
+
+            context::assemble(&passages, budget, self.grounded)
+        } else {
+            let passages = documents
+                .iter()
+                .enumerate()
+                .map(|(number, (_, document, text))| {
+                    if self.grounded {
+                        let label = context::numbered_label(number + 1, &document.metadata.label());
+
+                        format!("{label}\n{text}")
+                    } else {
+                        text.to_string()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n");
+
+            (passages, (0..documents.len()).collect())
+        };
+
+        if !self.grounded {
+            return (self.with_notes(passages), Vec::new());
+        }
+
+        let sources = order
+            .into_iter()
+            .enumerate()
+            .map(|(number, position)| {
+                let (index, document, text) = &documents[position];
+
+                Source {
+                    number: number + 1,
+                    document: *index,
+                    metadata: document.metadata.clone(),
+                    text: text.to_string(),
+                }
+            })
+            .collect();
+
+        let passages = if passages.is_empty() {
+            passages
+        } else {
+            format!("{CITATION_INSTRUCTION}\n\n{passages}")
+        };
+
+        (self.with_notes(passages), sources)
+    }
+
+    /// Entity cards and recalled memories, shown before the passages
+    fn notes(&self) -> String {
+        [self.cards.as_str(), self.memories.as_str()]
+            .into_iter()
+            .filter(|note| !note.is_empty())
             .collect::<Vec<_>>()
             .join("\n\n")
     }
 
+    fn with_notes(&self, passages: String) -> String {
+        let notes = self.notes();
+
+        if notes.is_empty() {
+            passages
+        } else {
+            format!("{notes}\n\n{passages}")
+        }
+    }
+
     pub fn update_context(&mut self, query: &str) -> String {
+        self.update_context_with_mode(query, RetrievalMode::Direct)
+    }
+
+    pub fn update_context_with_mode(&mut self, query: &str, mode: RetrievalMode) -> String {
         // a few words usually mean it's a simple answer to a question from the LLM
         // e.g. yes
         // queries or adjustments to what the LLM said would be longer
@@ -213,34 +895,18 @@ impl RAG {
             return self.context_to_string();
         }
 
-        for candicate in &mut self.current_context {
-            candicate.distance *= 1.5;
-        }
+        let top_k = retrieval_top_k(self.mmr_lambda.is_some());
 
-        if let Some(too_distant) = self
-            .current_context
-            .iter()
-            .position(|candidate| candidate.distance > 0.7)
-        {
-            self.current_context.truncate(too_distant);
-        }
+        let results = self
+            .index
+            .search_mode(query, &mode, top_k, DISTANCE_THRESHOLD);
 
-        for (index, distance) in self.search_threashold(query, 5, 0.3) {
-            if let Some(candidate) = self
-                .current_context
-                .iter_mut()
-                .find(|candidate| candidate.index == index)
-            {
-                // we want to favor documents that are relevant multiple rounds
-                if distance < candidate.distance {
-                    candidate.distance = distance;
-                } else {
-                    candidate.distance = (candidate.distance * 0.5).max(1.0);
-                }
-            } else {
-                self.current_context.push(Candidate { distance, index })
-            }
-        }
+        policy::update_pinned(
+            &self.pinned,
+            &mut *self.context_policy,
+            &mut self.current_context,
+            &results,
+        );
 
         self.current_context
             .sort_unstable_by(|candidate1, candidate2| {
@@ -250,15 +916,80 @@ impl RAG {
                     .unwrap()
             });
 
-        self.current_context.truncate(5);
+        if let Some(lambda) = self.mmr_lambda {
+            let candidates = self
+                .current_context
+                .iter()
+                .map(|candidate| (candidate.index, candidate.distance))
+                .collect::<Vec<_>>();
+
+            let centroids = self.index.read().document_centroids(
+                &candidates
+                    .iter()
+                    .map(|(index, _)| *index)
+                    .collect::<Vec<_>>(),
+            );
+
+            let mut selected =
+                mmr::maximal_marginal_relevance(&candidates, &centroids, lambda, CONTEXT_DOCUMENTS);
+            // keep the context sorted by distance
+            selected.sort_unstable();
+
+            self.current_context = selected
+                .into_iter()
+                .map(|position| {
+                    let (index, distance) = candidates[position];
+
+                    Candidate { distance, index }
+                })
+                .collect();
+        } else {
+            self.current_context.truncate(CONTEXT_DOCUMENTS);
+        }
+
+        if self.entity_cards {
+            self.cards = self
+                .index
+                .entity_cards(query)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+
+        self.recall_memories(query);
+
+        if self.parent_sections {
+            self.select_sections(query);
+        } else if let Some(max_distance) = self.compression {
+            self.compress_context(query, max_distance);
+        }
 
         self.context_to_string()
     }
+
+    /// Same as `update_context_with_mode` but `query` is first rewritten into a standalone search query
+    ///
+    /// `history` is ordered from oldest to newest and doesn't contain `query`.
+    pub fn update_context_with_history(
+        &mut self,
+        query: &str,
+        history: &[(Speaker, &str)],
+        rewriter: QueryRewriter,
+        mode: RetrievalMode,
+    ) -> String {
+        let query = rewriter.rewrite(query, history);
+
+        println!("Search query: {query}");
+
+        self.update_context_with_mode(&query, mode)
+    }
 }
 
-struct Candidate {
-    distance: f32,
-    index: usize,
+pub struct Candidate {
+    pub distance: f32,
+    /// Index of the document in the database
+    pub index: usize,
 }
 
 impl Debug for Candidate {
@@ -286,89 +1017,226 @@ impl Ord for Candidate {
     }
 }
 
-#[derive(Serialize, Deserialize)]
 struct VectorDB {
-    map: HnswMap<BertEmbeddings, usize>,
-    documents: Slab<Document>,
-    total_word_count: HashMap<String, u64>,
+    /// Decoded on the first embeddings search after loading, its vectors are rows of `vectors`
+    map: Lazy<VectorIndex>,
+    /// Vectors of the index in its quantization, memory-mapped once saved
+    vectors: Rows,
+    /// Only kept when the index is quantized and rescored
+    full_vectors: Option<FullVectors>,
+    documents: Lazy<Slab<Document>>,
+    /// Words counted since the database was loaded, the others are in `saved_word_count`
+    ///
+    /// Removing a document makes the count of its words negative.
+    total_word_count: HashMap<String, i64>,
+    saved_word_count: Option<WordCount>,
     average_word_count: f32,
-    #[serde(skip, default = "default_bert_model")]
-    bert: SentenceEmbeddingsModel,
-    file_hashes: HashMap<String, usize>,
+    embedder: Arc<dyn Embedder>,
+    file_hashes: Lazy<HashMap<String, usize>>,
+    /// Stored in its own file
+    cache: EmbeddingCache,
+    /// Generation of the segment files the database was loaded from
+    generation: Option<u64>,
+    graph: Lazy<EntityGraph>,
+    extractor: Box<dyn EntityExtractor + Send + Sync>,
+    /// Previous saves kept next to the database
+    backups: usize,
+    /// Maximum size of the sections of the documents, see `Index::set_section_size`
+    section_size: usize,
+    /// Embedding model of the vectors loaded from disk
+    vectors_model: Option<String>,
+}
+
+/// Where a document comes from
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Usually the path or url of the original file
+    pub source: String,
+    /// Page or chunk number inside the source
+    pub position: usize,
+    /// Documents summarized by this one, see `Index::build_summaries`
+    #[serde(default)]
+    pub children: Vec<usize>,
+    /// Set when the document is a row of a table, see `TableRow`
+    #[serde(default)]
+    pub table: Option<TableRow>,
+    /// Seconds since the Unix epoch when it was remembered, see `Memory`
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+impl Metadata {
+    fn label(&self) -> String {
+        if !self.children.is_empty() {
+            format!("[summary of {} documents]", self.children.len())
+        } else if self.source.is_empty() {
+            String::new()
+        } else {
+            format!("[{}, part {}]", self.source, self.position + 1)
+        }
+    }
+}
+
+/// Embeddings of a document, computed before it's added so encoding doesn't block searches
+struct EncodedDocument {
+    /// One per sentence
+    embeddings: Vec<Vec<f32>>,
+    /// Embeddings that weren't in the cache, by sentence hash
+    new_embeddings: HashMap<String, Vec<f32>>,
+    entities: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize)]
 struct Document {
-    text: String,
+    text: Text,
+    metadata: Metadata,
+    /// Terms counted by BM25, see `Language::analyze`
     individual_word_count: WordCount,
     word_count: u64,
+    language: Language,
+    /// Rows of the vectors of its sentences in `VectorDB::vectors`, in the order of the sentences
+    rows: Range<usize>,
+    /// Parents of its sentences, in the order of the text
+    sections: Vec<Section>,
 }
 
 impl VectorDB {
-    fn new() -> VectorDB {
-        let bert = default_bert_model();
-
+    fn new(embedder: Arc<dyn Embedder>) -> VectorDB {
         VectorDB {
-            map: instant_distance::Builder::default().build(Vec::new(), Vec::new()),
-            documents: Slab::new(),
+            map: Lazy::new(VectorIndex::new(Quantization::None)),
+            vectors: Rows::default(),
+            full_vectors: None,
+            documents: Lazy::default(),
             total_word_count: HashMap::new(),
+            saved_word_count: None,
             average_word_count: 0.0,
-            bert,
-            file_hashes: HashMap::new(),
+            embedder,
+            file_hashes: Lazy::default(),
+            cache: EmbeddingCache::default(),
+            generation: None,
+            graph: Lazy::default(),
+            extractor: Box::new(RuleExtractor),
+            backups: segment::BACKUPS,
+            section_size: parent::SECTION_SIZE,
+            vectors_model: None,
         }
     }
 
-    fn add_document(&mut self, text: String) {
-        let sha256 = sha256::digest(&text);
+    fn remove_document(&mut self, index: usize) -> bool {
+        if !self.documents.contains(index) {
+            return false;
+        }
 
-        if self.file_hashes.contains_key(&sha256) {
+        let document = self.documents.remove(index);
+
+        self.file_hashes.retain(|_, key| *key != index);
+        self.graph.remove(index, &document.text);
+
+        let mut stream = document.individual_word_count.0.stream();
+        while let Some((word, count)) = stream.next() {
+            *self
+                .total_word_count
+                .entry(String::from_utf8_lossy(word).to_string())
+                .or_default() -= count as i64;
+        }
+
+        let documents = self.documents.len() as f32;
+        self.average_word_count = if documents > 0.0 {
+            self.average_word_count
+                .mul_add(documents + 1.0, -(document.word_count as f32))
+                / documents
+        } else {
+            0.0
+        };
+
+        self.map.retain(|key| key.document != index);
+
+        true
+    }
+
+    /// Embeddings of the sentences of `text`, `None` if there's nothing to add
+    fn encode_document(&self, text: &str) -> Option<EncodedDocument> {
+        if self.file_hashes.contains_key(&sha256::digest(text)) {
             println!("Document already present");
 
-            return;
+            return None;
         }
 
-        let sentences = text
-            .split(END_OF_SENTENCE)
-            .filter(|sentence| !sentence.is_empty())
+        let sentences = sentences(text)
+            .into_iter()
+            .map(|sentence| sentence.trim_end_matches(END_OF_SENTENCE))
             .collect::<Vec<_>>();
 
         if sentences.is_empty() {
-            return;
+            return None;
+        }
+
+        let mut encoded = self.encode_cached(&sentences);
+        encoded.entities = self.extractor.extract(text);
+
+        Some(encoded)
+    }
+
+    fn add_document(
+        &mut self,
+        text: String,
+        metadata: Metadata,
+        encoded: EncodedDocument,
+    ) -> Option<usize> {
+        let sha256 = sha256::digest(&text);
+
+        // another writer added it while this one was encoding
+        if self.file_hashes.contains_key(&sha256) {
+            return None;
+        }
+
+        let sentences = sentences(&text)
+            .into_iter()
+            .map(|sentence| sentence.trim_end_matches(END_OF_SENTENCE))
+            .collect::<Vec<_>>();
+
+        let model = self.embedder.id().to_string();
+        for (hash, embedding) in encoded.new_embeddings {
+            self.cache.insert(&model, hash, embedding);
         }
 
         let key = self.documents.vacant_key();
 
-        let progress = indicatif::ProgressBar::new(sentences.len() as u64).with_style(
-            ProgressStyle::default_bar()
-                .template("{pos}/{len} {elapsed} {bar:80}")
-                .unwrap()
-                .progress_chars("#.-"),
-        );
+        self.graph.add(key, &text, &encoded.entities);
+
+        let embeddings = encoded.embeddings;
+        let rows = self.vectors.len()..self.vectors.len() + embeddings.len();
+        if let Some(full_vectors) = &mut self.full_vectors {
+            // both have a row for each vector of the index
+            let first_row = full_vectors.extend(&embeddings);
+            assert_eq!(first_row, self.vectors.len());
+        }
 
         self.add_embeddings(
-            self.bert
-                .encode(&sentences)
-                .unwrap()
+            embeddings
                 .into_iter()
-                .map(|embedding| {
-                    progress.inc(1);
-
-                    (embedding, key)
-                }),
+                .enumerate()
+                .map(|(sentence, embedding)| {
+                    (
+                        embedding,
+                        SentenceKey {
+                            document: key,
+                            sentence,
+                            row: 0,
+                        },
+                    )
+                })
+                .collect(),
         );
 
-        progress.finish();
+        let language = Language::detect(&text).unwrap_or_default();
 
         let mut word_count = 0;
         let mut individual_word_count: HashMap<String, u64> = HashMap::new();
         for sentence in sentences {
-            for word in sentence.split_terminator(&[' ', ',', ':', '"']) {
-                if !word.is_empty() {
-                    let word = word.to_lowercase();
-                    *individual_word_count.entry(word.clone()).or_default() += 1;
-                    *self.total_word_count.entry(word).or_default() += 1;
-                    word_count += 1;
-                }
+            for word in language.analyze(sentence) {
+                *individual_word_count.entry(word.clone()).or_default() += 1;
+                *self.total_word_count.entry(word).or_default() += 1;
+                word_count += 1;
             }
         }
 
@@ -383,10 +1251,18 @@ impl VectorDB {
 
         fst_map.extend_iter(individual_word_count).unwrap();
 
+        let sections = parent::sections(&text, self.section_size);
+
         let doc = Document {
-            text,
-            individual_word_count: WordCount(fst_map.into_map()),
+            text: Text::from(text),
+            metadata,
+            individual_word_count: WordCount(
+                fst::Map::new(Bytes::Owned(fst_map.into_inner().unwrap().into())).unwrap(),
+            ),
             word_count,
+            language,
+            rows,
+            sections,
         };
 
         self.average_word_count = self
@@ -396,127 +1272,548 @@ impl VectorDB {
 
         self.file_hashes.insert(sha256, key);
 
-        self.documents.insert(doc);
+        Some(self.documents.insert(doc))
+    }
+
+    /// Encodes every document with the current embedder, the quantization is kept
+    fn encode_again(&mut self) {
+        let quantization = self.map.quantization();
+        let rescore = self.full_vectors.is_some();
+
+        let model = self.embedder.id().to_string();
+        let mut vectors = Vec::new();
+
+        for (document, entry) in self.documents.iter() {
+            let sentences = sentences(&entry.text)
+                .into_iter()
+                .map(|sentence| sentence.trim_end_matches(END_OF_SENTENCE))
+                .collect::<Vec<_>>();
+
+            let encoded = self.encode_cached(&sentences);
+
+            for (hash, embedding) in encoded.new_embeddings {
+                self.cache.insert(&model, hash, embedding);
+            }
+
+            vectors.extend(encoded.embeddings.into_iter().enumerate().map(
+                |(sentence, embedding)| {
+                    (
+                        embedding,
+                        SentenceKey {
+                            document,
+                            sentence,
+                            row: 0,
+                        },
+                    )
+                },
+            ));
+        }
+
+        self.rebuild(quantization, rescore, vectors);
+
+        self.vectors_model = Some(model);
     }
 
-    fn add_embeddings(&mut self, embeddings_and_keys: impl Iterator<Item = (Vec<f32>, usize)>) {
-        let (points, values) = self
-            .map
+    /// Only encodes the sentences that aren't in the cache
+    fn encode_cached(&self, sentences: &[&str]) -> EncodedDocument {
+        let model = self.embedder.id();
+
+        let hashes = sentences
             .iter()
-            .map(|(point_id, point)| {
-                let value = self.map.values[point_id.into_inner() as usize];
+            .map(|sentence| sha256::digest(*sentence))
+            .collect::<Vec<_>>();
 
-                (point.clone(), value)
-            })
-            .chain(
-                embeddings_and_keys
-                    .into_iter()
-                    .map(|(embedding, key)| (BertEmbeddings(embedding), key)),
-            )
+        let (missing_hashes, missing_sentences): (Vec<_>, Vec<_>) = hashes
+            .iter()
+            .zip(sentences)
+            .filter(|(hash, _)| self.cache.get(model, hash).is_none())
+            .map(|(hash, sentence)| (hash.clone(), *sentence))
             .unzip();
 
-        self.map = instant_distance::Builder::default().build(points, values);
+        let new_embeddings = if missing_sentences.is_empty() {
+            HashMap::new()
+        } else {
+            let progress = indicatif::ProgressBar::new(missing_sentences.len() as u64).with_style(
+                ProgressStyle::default_bar()
+                    .template("{pos}/{len} {elapsed} {bar:80}")
+                    .unwrap()
+                    .progress_chars("#.-"),
+            );
+
+            let embeddings = missing_sentences
+                .chunks(ENCODING_BATCH)
+                .flat_map(|batch| {
+                    let embeddings = self.embedder.encode(batch);
+                    progress.inc(batch.len() as u64);
+
+                    embeddings
+                })
+                .collect::<Vec<_>>();
+
+            progress.finish();
+
+            missing_hashes
+                .into_iter()
+                .zip(embeddings)
+                .collect::<HashMap<_, _>>()
+        };
+
+        println!(
+            "{} sentences encoded, {} from the cache",
+            missing_sentences.len(),
+            sentences.len() - missing_sentences.len()
+        );
+
+        let embeddings = hashes
+            .iter()
+            .map(|hash| {
+                new_embeddings
+                    .get(hash)
+                    .cloned()
+                    .or_else(|| self.cache.get(model, hash))
+                    .unwrap()
+            })
+            .collect();
+
+        EncodedDocument {
+            embeddings,
+            new_embeddings,
+            entities: Vec::new(),
+        }
+    }
+
+    fn add_embeddings(&mut self, embeddings_and_keys: Vec<(Vec<f32>, SentenceKey)>) {
+        self.map.add(&mut self.vectors, embeddings_and_keys);
+    }
+
+    fn embed(&self, text: &str) -> BertEmbeddings {
+        BertEmbeddings(self.embedder.encode(&[text]).remove(0))
+    }
+
+    fn search_embeddings(&self, query: &str, top_k: usize) -> Vec<(usize, f32)> {
+        self.search_sentences(query, top_k)
+            .into_iter()
+            .map(|(key, distance)| (key.document, distance))
+            .collect()
     }
 
-    fn search_embeddings(&self, query: &str, mut top_k: usize) -> Vec<(usize, f32)> {
-        let embeddings = self.bert.encode(&[query]).unwrap().remove(0);
+    /// Closest sentence of the `top_k` closest documents
+    fn search_sentences(&self, query: &str, mut top_k: usize) -> Vec<(SentenceKey, f32)> {
+        let embeddings = self.embed(query);
 
         top_k = top_k.min(self.documents.len());
 
-        let mut candidates: Vec<Candidate> = Vec::with_capacity(top_k);
+        let mut results = match &self.full_vectors {
+            Some(full_vectors) => {
+                let mut results = self.map.search(&embeddings.0, top_k * RESCORING_FACTOR);
+
+                let rows = results.iter().map(|(key, _)| key.row).collect::<Vec<_>>();
+                for ((_, distance), vector) in results.iter_mut().zip(full_vectors.get(&rows)) {
+                    *distance = embeddings.distance(&BertEmbeddings(vector));
+                }
+
+                results.sort_by(|(_, distance1), (_, distance2)| distance1.total_cmp(distance2));
 
-        let mut search = Search::default();
-        for item in self.map.search(&BertEmbeddings(embeddings), &mut search) {
-            if candidates
+                results
+            }
+            None => self.map.search(&embeddings.0, top_k),
+        };
+
+        let mut candidates: Vec<(SentenceKey, f32)> = Vec::with_capacity(top_k);
+        for (key, distance) in results.drain(..) {
+            if candidates.len() == top_k {
+                break;
+            }
+
+            if !candidates
                 .iter()
-                .find(|candidate| candidate.index == *item.value)
-                .is_none()
+                .any(|(candidate, _)| candidate.document == key.document)
             {
-                candidates.push(Candidate {
-                    distance: item.distance,
-                    index: *item.value,
-                });
-
-                if candidates.len() == top_k {
-                    break;
-                }
+                candidates.push((key, distance));
             }
         }
 
         candidates
+    }
+
+    /// Replaces dequantized vectors by their full precision version when it's kept
+    fn full_precision(
+        &self,
+        vectors: Vec<(Vec<f32>, SentenceKey)>,
+    ) -> Vec<(Vec<f32>, SentenceKey)> {
+        let Some(full_vectors) = &self.full_vectors else {
+            return vectors;
+        };
+
+        let rows = vectors.iter().map(|(_, key)| key.row).collect::<Vec<_>>();
+
+        full_vectors
+            .get(&rows)
             .into_iter()
-            .map(|candidate| (candidate.index, candidate.distance))
+            .zip(vectors)
+            .map(|(vector, (_, key))| (vector, key))
             .collect()
     }
 
-    /// https://en.m.wikipedia.org/wiki/Okapi_BM25
-    fn bm35_plus(&self, query: &str) -> Vec<(usize, f32)> {
-        const K1: f32 = 1.2;
-        const B: f32 = 0.75;
-        const DELTA: f32 = 1.0;
+    /// Dimensions of the vectors of the index, `None` while it's empty
+    fn dimensions(&self) -> Option<usize> {
+        let row = self.vectors.get(0)?;
 
-        let doc_count = self.documents.len() as f32;
+        Some(self.map.quantization().decode(row.as_ref()).len())
+    }
 
-        // It would be better to collect to a HashMap
-        // this way if a word is present multiple times
-        // we don't calculate the score multiple times
-        let words_lowercase = query
-            .split_terminator(|c| END_OF_SENTENCE.contains(&c) || [' ', ',', ':', '"'].contains(&c))
-            .filter(|word| !word.is_empty())
-            .map(|word| word.to_lowercase())
-            .collect::<Vec<_>>();
+    /// Vectors of all the sentences of `documents`, read from their rows without going through the index
+    fn document_vectors(&self, documents: &[usize]) -> Vec<(Vec<f32>, SentenceKey)> {
+        let quantization = self.map.quantization();
+        let mut vectors = Vec::new();
 
-        let idfs = words_lowercase
-            .iter()
-            .map(|word| {
-                let doc_containing_word = self
-                    .documents
+        for &document in documents {
+            let Some(entry) = self.documents.get(document) else {
+                continue;
+            };
+
+            let rows = entry.rows.clone().collect::<Vec<_>>();
+            let document_vectors = match &self.full_vectors {
+                Some(full_vectors) => full_vectors.get(&rows),
+                None => rows
                     .iter()
-                    .filter(|(_, doc)| doc.individual_word_count.0.contains_key(word.as_bytes()))
-                    .count() as f32;
+                    .map(|&row| quantization.decode(self.vectors.get(row).unwrap().as_ref()))
+                    .collect(),
+            };
+
+            vectors.extend(document_vectors.into_iter().zip(rows).enumerate().map(
+                |(sentence, (vector, row))| {
+                    (
+                        vector,
+                        SentenceKey {
+                            document,
+                            sentence,
+                            row,
+                        },
+                    )
+                },
+            ));
+        }
 
-                ((doc_count - doc_containing_word + 0.5) / (doc_containing_word + 0.5) + 1.0).ln()
-            })
-            .collect::<Vec<_>>();
+        vectors
+    }
+
+    /// Rebuilds the index with `quantization`, see `RAG::set_quantization`
+    ///
+    /// Only the loaded vectors change, the saved segments are replaced on the next save.
+    fn quantize(&mut self, quantization: Quantization, rescore: bool) {
+        let vectors = self.full_precision(self.map.vectors());
+
+        self.rebuild(quantization, rescore, vectors);
+    }
+
+    /// Replaces the index and the rows of its vectors, without the rows of removed documents
+    fn rebuild(
+        &mut self,
+        quantization: Quantization,
+        rescore: bool,
+        mut vectors: Vec<(Vec<f32>, SentenceKey)>,
+    ) {
+        // the sentences of a document get consecutive rows
+        vectors.sort_by_key(|(_, key)| (key.document, key.sentence));
+
+        for (_, document) in self.documents.iter_mut() {
+            document.rows = 0..0;
+        }
+
+        for (row, (_, key)) in vectors.iter().enumerate() {
+            if let Some(document) = self.documents.get_mut(key.document) {
+                if document.rows.is_empty() {
+                    document.rows = row..row;
+                }
+
+                document.rows.end = row + 1;
+            }
+        }
+
+        self.full_vectors = None;
+
+        if rescore && quantization != Quantization::None {
+            let mut full_vectors = FullVectors::new();
+
+            // rows follow the order of `vectors` in both
+            full_vectors.extend(
+                &vectors
+                    .iter()
+                    .map(|(vector, _)| vector.clone())
+                    .collect::<Vec<_>>(),
+            );
+
+            self.full_vectors = Some(full_vectors);
+        }
+
+        self.vectors = Rows::default();
+        self.map = Lazy::new(VectorIndex::build(quantization, &mut self.vectors, vectors));
+    }
+
+    /// Average of the sentence embeddings of each document, normalized
+    fn document_centroids(&self, indices: &[usize]) -> HashMap<usize, BertEmbeddings> {
+        let mut centroids: HashMap<usize, BertEmbeddings> = HashMap::new();
+
+        for (vector, key) in self.document_vectors(indices) {
+            let centroid = centroids
+                .entry(key.document)
+                .or_insert_with(|| BertEmbeddings(vec![0.0; vector.len()]));
+
+            for (sum, value) in centroid.0.iter_mut().zip(&vector) {
+                *sum += value;
+            }
+        }
+
+        for centroid in centroids.values_mut() {
+            let magnitude = centroid.magnitude();
+
+            if magnitude > 0.0 {
+                for value in &mut centroid.0 {
+                    *value /= magnitude;
+                }
+            }
+        }
+
+        centroids
+    }
+
+    /// Distance between `query` and each sentence of `document`, in the order of `sentences`
+    fn sentence_distances(&self, document: usize, query: &BertEmbeddings) -> Vec<f32> {
+        let mut distances = Vec::new();
+
+        for (vector, key) in self.document_vectors(&[document]) {
+            if distances.len() <= key.sentence {
+                distances.resize(key.sentence + 1, f32::MAX);
+            }
+
+            distances[key.sentence] = query.distance(&BertEmbeddings(vector));
+        }
+
+        distances
+    }
+
+    /// https://en.m.wikipedia.org/wiki/Okapi_BM25
+    /// Scores the documents in the language of `query`, or all of them when it can't be detected
+    ///
+    /// Each document is scored with the terms of the query analyzed in its own language.
+    /// Documents in other languages are left to the embeddings search.
+    fn bm35_plus(&self, query: &str) -> Vec<(usize, f32)> {
+        let query_language = Language::detect(query);
+
+        // terms of the query, their idf and the average document length, by document language
+        let mut terms: HashMap<Language, (Vec<String>, Vec<f32>, f32)> = HashMap::new();
 
         let mut scores = self
             .documents
             .iter()
-            .map(|(_, doc)| {
-                words_lowercase
+            .filter(|(_, doc)| query_language.is_none_or(|language| language == doc.language))
+            .map(|(key, doc)| {
+                let (words, idfs, average_word_count) =
+                    terms.entry(doc.language).or_insert_with(|| {
+                        let words = doc.language.analyze(query);
+                        let idfs = words
+                            .iter()
+                            .map(|word| self.bm25_idf(word, doc.language))
+                            .collect();
+
+                        (words, idfs, self.average_word_count(doc.language))
+                    });
+
+                let score = words
                     .iter()
                     .zip(idfs.iter())
-                    .map(|(word, idf)| {
-                        let doc_word_count = doc
-                            .individual_word_count
-                            .0
-                            .get(word.as_bytes())
-                            .unwrap_or(0) as f32;
-
-                        let doc_total_word_count = doc.word_count as f32;
-                        let average_word_count = self.average_word_count;
-
-                        idf * ((doc_word_count * (K1 + 1.0)
-                            / (doc_word_count
-                                + K1 * (1.0 - B + B * doc_total_word_count / average_word_count)))
-                            + DELTA)
-                    })
-                    .sum::<f32>()
+                    .map(|(word, idf)| self.bm25_term(doc, word, *idf, *average_word_count).0)
+                    .sum::<f32>();
+
+                (key, score)
             })
-            .enumerate()
             .collect::<Vec<(usize, f32)>>();
 
-        scores.sort_unstable_by(|(_, score1), (_, score2)| score2.partial_cmp(score1).unwrap());
+        scores.sort_unstable_by(|(_, score1), (_, score2)| score2.total_cmp(score1));
 
         scores
 
         // When we switch to cross-encoder
         // scores.into_iter().map(|(index, _)| index).collect()
     }
+
+    /// Only counts the documents in `language`, the same word can be common in one language and rare in another
+    fn bm25_idf(&self, word: &str, language: Language) -> f32 {
+        let (doc_count, doc_containing_word) = self
+            .documents
+            .iter()
+            .filter(|(_, doc)| doc.language == language)
+            .fold((0.0, 0.0), |(doc_count, containing), (_, doc)| {
+                let contains = doc.individual_word_count.0.contains_key(word.as_bytes());
+
+                (doc_count + 1.0, containing + f32::from(u8::from(contains)))
+            });
+
+        ((doc_count - doc_containing_word + 0.5) / (doc_containing_word + 0.5) + 1.0).ln()
+    }
+
+    /// Average number of words of the documents in `language`
+    fn average_word_count(&self, language: Language) -> f32 {
+        let (doc_count, word_count) = self
+            .documents
+            .iter()
+            .filter(|(_, doc)| doc.language == language)
+            .fold((0, 0), |(doc_count, word_count), (_, doc)| {
+                (doc_count + 1, word_count + doc.word_count)
+            });
+
+        word_count as f32 / doc_count.max(1) as f32
+    }
+
+    /// Score of `word` for `doc` and the number of times it appears in it
+    fn bm25_term(
+        &self,
+        doc: &Document,
+        word: &str,
+        idf: f32,
+        average_word_count: f32,
+    ) -> (f32, u64) {
+        const K1: f32 = 1.2;
+        const B: f32 = 0.75;
+        const DELTA: f32 = 1.0;
+
+        let doc_word_count = doc
+            .individual_word_count
+            .0
+            .get(word.as_bytes())
+            .unwrap_or(0);
+
+        let term_frequency = doc_word_count as f32;
+        let doc_total_word_count = doc.word_count as f32;
+
+        let score = idf
+            * ((term_frequency * (K1 + 1.0)
+                / (term_frequency
+                    + K1 * (1.0 - B + B * doc_total_word_count / average_word_count)))
+                + DELTA);
+
+        (score, doc_word_count)
+    }
+}
+
+/// Documents searched for the context, MMR needs more candidates than it keeps to have something to choose from
+fn retrieval_top_k(mmr: bool) -> usize {
+    if mmr {
+        CONTEXT_DOCUMENTS * 3
+    } else {
+        CONTEXT_DOCUMENTS
+    }
+}
+
+/// Merges bm25 and embeddings results into distances, sorted from closest to furthest
+fn fuse(
+    mut bm25_results: Vec<(usize, f32)>,
+    embeddings_results: &[(usize, f32)],
+) -> Vec<(usize, f32)> {
+    // TODO: use a cross-encoder
+    //  we're currently merging embeddings and bm25 scores
+    //  this is not mathematically correct
+
+    // the best score is 0 when no word of the query is counted, e.g. only stop words
+    if let Some(&(_, last_bm25)) = bm25_results.first() {
+        for (_, score) in &mut bm25_results {
+            *score = if last_bm25 > 0.0 {
+                1.0 - *score / last_bm25
+            } else {
+                1.0
+            };
+        }
+    }
+
+    let bm25_documents = bm25_results
+        .iter()
+        .map(|(index, _)| *index)
+        .collect::<HashSet<_>>();
+
+    let mut results = bm25_results
+        .into_iter()
+        .map(|(bm25_index, mut score)| {
+            if let Some(embeddings_score) =
+                embeddings_results
+                    .iter()
+                    .find_map(|&(embeddings_index, embeddings_score)| {
+                        (bm25_index == embeddings_index).then(|| embeddings_score)
+                    })
+            {
+                score = (score + embeddings_score) / 2.0;
+            }
+
+            (bm25_index, score)
+        })
+        .collect::<Vec<_>>();
+
+    // documents in another language than the query only have their embeddings distance
+    results.extend(
+        embeddings_results
+            .iter()
+            .filter(|(index, _)| !bm25_documents.contains(index))
+            .copied(),
+    );
+
+    results.sort_unstable_by(|(_, score1), (_, score2)| score1.total_cmp(score2));
+
+    results
+}
+
+/// Cuts `content` in chunks of at most `chunk_size` bytes, each chunk overlaps the previous one by `overlap` bytes
+fn chunks(content: &str, chunk_size: usize, overlap: usize) -> Vec<&str> {
+    assert!(overlap < chunk_size);
+
+    if content.len() <= chunk_size {
+        return vec![content];
+    }
+
+    let mut chunks = Vec::new();
+
+    let mut chunk_start = 0;
+    loop {
+        if chunk_start + chunk_size >= content.len() {
+            chunks.push(&content[chunk_start..]);
+
+            break;
+        }
+
+        let chunk_end = (chunk_start..chunk_start + chunk_size)
+            .rev()
+            .find(|&i| content.is_char_boundary(i))
+            .unwrap();
+
+        chunks.push(&content[chunk_start..chunk_end]);
+
+        chunk_start = (chunk_start..chunk_end - overlap)
+            .rev()
+            .find(|&i| content.is_char_boundary(i))
+            .unwrap();
+    }
+
+    chunks
+}
+
+/// Identifies the sentence an embedding comes from
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct SentenceKey {
+    document: usize,
+    /// Position in `sentences(document.text)`
+    sentence: usize,
+    /// Position in `VectorDB::vectors`, and in `VectorDB::full_vectors` when the index is rescored
+    row: usize,
+}
+
+/// Sentences of `text` with their end of sentence character, in the same order as their embeddings
+fn sentences(text: &str) -> Vec<&str> {
+    text.split_inclusive(END_OF_SENTENCE)
+        .filter(|sentence| !sentence.trim_end_matches(END_OF_SENTENCE).is_empty())
+        .collect()
 }
 
 #[derive(Clone, Serialize, Deserialize)]
-struct BertEmbeddings(Vec<f32>);
+pub(crate) struct BertEmbeddings(Vec<f32>);
 
 impl BertEmbeddings {
     fn dot_product(&self, other: &BertEmbeddings) -> f32 {
@@ -525,12 +1822,10 @@ impl BertEmbeddings {
         self.0.iter().zip(other.0.iter()).map(|(a, b)| a * b).sum()
     }
 
-    #[allow(unused)]
     fn cosine_similarity(&self, other: &BertEmbeddings) -> f32 {
         self.dot_product(other) / (self.magnitude() * other.magnitude())
     }
 
-    #[allow(unused)]
     fn magnitude(&self) -> f32 {
         self.0.iter().map(|v| v.powi(2)).sum::<f32>().sqrt()
     }
@@ -543,43 +1838,10 @@ impl Point for BertEmbeddings {
     }
 }
 
-fn default_bert_model() -> SentenceEmbeddingsModel {
-    SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllDistilrobertaV1)
-        .create_model()
-        .unwrap()
+/// Multilingual so questions can be asked in another language than the documents
+fn default_embedder() -> Arc<dyn Embedder> {
+    Arc::new(BertEmbedder::multilingual())
 }
 
 #[derive(Debug)]
-struct WordCount(fst::Map<Vec<u8>>);
-
-impl Serialize for WordCount {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serializer.serialize_bytes(self.0.as_fst().as_bytes())
-    }
-}
-
-impl<'de> Deserialize<'de> for WordCount {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        struct WordCountVisitor;
-
-        impl<'de> Visitor<'de> for WordCountVisitor {
-            type Value = WordCount;
-
-            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("word count fst")
-            }
-
-            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
-                Ok(WordCount(fst::Map::new(v.to_vec()).unwrap()))
-            }
-        }
-
-        deserializer.deserialize_bytes(WordCountVisitor)
-    }
-}
+struct WordCount(fst::Map<Bytes>);
@@ -0,0 +1,241 @@
+use crate::{Embedder, Index, Metadata, OpenError, TextGenerator, DISTANCE_THRESHOLD, RAG};
+use serde::Serialize;
+use shared::Speaker;
+use std::{
+    fmt::Display,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MEMORY_PATH: &str = "./resources/memory.data";
+/// Source of the documents of a `Memory`
+pub const MEMORY_SOURCE: &str = "memory";
+/// Memories put in the context for each query
+const RECALLED_MEMORIES: usize = 3;
+/// Memories stored before writing a new generation of the database, the rest are saved on drop
+const MEMORIES_PER_SAVE: usize = 10;
+
+/// Earlier turns of the conversation kept in their own database, across restarts
+///
+/// Turns dropped from the LLM history are stored as is or as facts about the user,
+/// the ones related to the query are recalled next to the documents, see `RAG::set_memory`.
+/// Each save writes a new generation and rotates a backup, memories are saved in batches and on drop.
+pub struct Memory {
+    index: Index,
+    /// Memories stored since the last save
+    unsaved: AtomicUsize,
+}
+
+impl Memory {
+    /// Memory saved in `./resources/memory.data`, see `Memory::open_at`
+    pub fn open(embedder: Arc<dyn Embedder>) -> Result<Memory, OpenError> {
+        Memory::open_at(MEMORY_PATH, embedder)
+    }
+
+    /// `embedder` is usually the one of the documents, `Index::embedder`, so the model is only loaded once
+    ///
+    /// Memories saved with another model are encoded again.
+    pub fn open_at(
+        path: impl AsRef<Path>,
+        embedder: Arc<dyn Embedder>,
+    ) -> Result<Memory, OpenError> {
+        Ok(Memory {
+            index: Index::open_at_sharing(path, embedder)?,
+            unsaved: AtomicUsize::new(0),
+        })
+    }
+
+    /// Memory that is forgotten when dropped
+    pub fn in_memory(embedder: Box<dyn Embedder>) -> Memory {
+        Memory {
+            index: Index::in_memory(embedder),
+            unsaved: AtomicUsize::new(0),
+        }
+    }
+
+    /// Memories are documents of this index, e.g. to remove one
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Stores `turns` as a single memory, returns `false` if there was nothing new to remember
+    ///
+    /// `turns` are ordered from oldest to newest, `user` and `assistant` are the names used in the conversation.
+    pub fn remember_turns(&self, turns: &[(Speaker, String)], user: &str, assistant: &str) -> bool {
+        if turns.is_empty() {
+            return false;
+        }
+
+        let remembered = self.remember(transcript(turns, user, assistant), now());
+
+        self.stored(remembered as usize);
+
+        remembered
+    }
+
+    /// Asks `generator` for the facts about the user in `turns` and stores each one
+    ///
+    /// Returns the number of new facts. Facts are shorter than the turns so more of them fit in the context.
+    pub fn remember_facts(
+        &self,
+        generator: &dyn TextGenerator,
+        turns: &[(Speaker, String)],
+        user: &str,
+        assistant: &str,
+    ) -> usize {
+        if turns.is_empty() {
+            return 0;
+        }
+
+        let prompt = format!(
+            "Below is a part of a conversation between {user} and {assistant}. \
+            List what it tells about {user}: preferences, plans, people, things they own or did. \
+            One short sentence per line, reply with \"none\" if there isn't anything.\n\n\
+            {}",
+            transcript(turns, user, assistant)
+        );
+
+        let timestamp = now();
+
+        let remembered = generator
+            .generate(&prompt, 150)
+            .lines()
+            .map(|line| {
+                line.trim_start_matches(|c: char| {
+                    c.is_ascii_digit() || c.is_whitespace() || "-*•.)".contains(c)
+                })
+                .trim()
+            })
+            .filter(|line| !line.is_empty() && !line.eq_ignore_ascii_case("none"))
+            .filter(|fact| self.remember(fact.to_string(), timestamp))
+            .count();
+
+        self.stored(remembered);
+
+        remembered
+    }
+
+    /// Saves the memories stored since the last save, if any
+    pub fn save(&self) {
+        if self.unsaved.swap(0, Ordering::Relaxed) > 0 {
+            self.index.save();
+        }
+    }
+
+    fn stored(&self, count: usize) {
+        if self.unsaved.fetch_add(count, Ordering::Relaxed) + count >= MEMORIES_PER_SAVE {
+            self.save();
+        }
+    }
+
+    fn remember(&self, text: String, timestamp: u64) -> bool {
+        self.index
+            .add_with_metadata(
+                text,
+                Metadata {
+                    source: MEMORY_SOURCE.to_string(),
+                    timestamp: Some(timestamp),
+                    ..Metadata::default()
+                },
+            )
+            .is_some()
+    }
+
+    /// Memories closest to `query`, closest first
+    pub fn recall(&self, query: &str, count: usize) -> Vec<Recollection> {
+        self.index
+            .search(query, count, DISTANCE_THRESHOLD)
+            .into_iter()
+            .map(|result| Recollection {
+                text: result.text,
+                timestamp: result.metadata.timestamp.unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+fn transcript(turns: &[(Speaker, String)], user: &str, assistant: &str) -> String {
+    turns
+        .iter()
+        .map(|(speaker, text)| {
+            let name = match speaker {
+                Speaker::User => user,
+                Speaker::Assistant => assistant,
+            };
+
+            format!("{name}: {text}")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// A memory recalled for a query
+#[derive(Debug, Serialize)]
+pub struct Recollection {
+    pub text: String,
+    /// Seconds since the Unix epoch when it was remembered
+    pub timestamp: u64,
+}
+
+impl Display for Recollection {
+    /// `[3 days ago] Leudz: I finished the wires module`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = now().saturating_sub(self.timestamp);
+
+        let (count, unit) = match seconds {
+            0..=59 => return write!(f, "[just now] {}", self.text),
+            60..=3599 => (seconds / 60, "minute"),
+            3600..=86399 => (seconds / 3600, "hour"),
+            _ => (seconds / 86400, "day"),
+        };
+        let plural = if count > 1 { "s" } else { "" };
+
+        write!(f, "[{count} {unit}{plural} ago] {}", self.text)
+    }
+}
+
+impl RAG {
+    /// Recalls the memories related to each query and puts them before the passages, `None` disables it
+    ///
+    /// Storing the turns is up to the caller, with `Memory::remember_turns` or `Memory::remember_facts`.
+    pub fn set_memory(&mut self, memory: Option<Arc<Memory>>) {
+        self.memory = memory;
+        self.memories.clear();
+    }
+
+    pub(crate) fn recall_memories(&mut self, query: &str) {
+        let Some(memory) = &self.memory else {
+            return;
+        };
+
+        let recollections = memory.recall(query, RECALLED_MEMORIES);
+
+        self.memories = if recollections.is_empty() {
+            String::new()
+        } else {
+            let recollections = recollections
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+
+            format!("From earlier conversations:\n{}", recollections.join("\n"))
+        };
+    }
+}
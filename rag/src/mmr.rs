@@ -0,0 +1,95 @@
+use crate::BertEmbeddings;
+use std::collections::HashMap;
+
+/// Maximal Marginal Relevance
+///
+/// Picks `count` candidates one at a time, each time the one with the best trade-off between
+/// its relevance and how different it is from the ones already picked.
+/// `lambda` at 1.0 only looks at relevance, at 0.0 only at diversity.
+///
+/// `candidates` are (document index, distance) and the returned values are positions in `candidates`.
+pub(crate) fn maximal_marginal_relevance(
+    candidates: &[(usize, f32)],
+    embeddings: &HashMap<usize, BertEmbeddings>,
+    lambda: f32,
+    count: usize,
+) -> Vec<usize> {
+    let mut selected: Vec<usize> = Vec::with_capacity(count.min(candidates.len()));
+
+    while selected.len() < count.min(candidates.len()) {
+        let best = candidates
+            .iter()
+            .enumerate()
+            .filter(|(position, _)| !selected.contains(position))
+            .map(|(position, (index, distance))| {
+                let relevance = 1.0 - distance;
+
+                let redundancy = selected
+                    .iter()
+                    .filter_map(|&selected_position| {
+                        let selected_index = candidates[selected_position].0;
+
+                        Some(
+                            embeddings
+                                .get(index)?
+                                .cosine_similarity(embeddings.get(&selected_index)?),
+                        )
+                    })
+                    .fold(0.0f32, f32::max);
+
+                (position, lambda * relevance - (1.0 - lambda) * redundancy)
+            })
+            .max_by(|(_, score1), (_, score2)| score1.total_cmp(score2));
+
+        match best {
+            Some((position, _)) => selected.push(position),
+            None => break,
+        }
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embeddings(vectors: &[&[f32]]) -> HashMap<usize, BertEmbeddings> {
+        vectors
+            .iter()
+            .enumerate()
+            .map(|(index, vector)| (index, BertEmbeddings(vector.to_vec())))
+            .collect()
+    }
+
+    #[test]
+    fn a_diverse_document_is_picked_over_a_near_duplicate() {
+        let candidates = [(0, 0.1), (1, 0.12), (2, 0.3)];
+        // 1 is almost the same as 0, 2 is about something else
+        let embeddings = embeddings(&[&[1.0, 0.0], &[0.99, 0.1], &[0.0, 1.0]]);
+
+        assert_eq!(
+            maximal_marginal_relevance(&candidates, &embeddings, 0.5, 2),
+            [0, 2]
+        );
+        // only relevance
+        assert_eq!(
+            maximal_marginal_relevance(&candidates, &embeddings, 1.0, 2),
+            [0, 1]
+        );
+    }
+
+    #[test]
+    fn every_candidate_is_picked_at_most_once() {
+        let candidates = [(4, 0.2), (7, 0.1)];
+        // 7 has no embeddings, it can't be redundant
+        let embeddings = HashMap::from([(4, BertEmbeddings(vec![1.0, 0.0]))]);
+
+        assert_eq!(
+            maximal_marginal_relevance(&candidates, &embeddings, 0.5, 5),
+            [1, 0]
+        );
+        assert!(maximal_marginal_relevance(&[], &embeddings, 0.5, 5).is_empty());
+        assert!(maximal_marginal_relevance(&candidates, &embeddings, 0.5, 0).is_empty());
+    }
+}
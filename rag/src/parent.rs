@@ -0,0 +1,124 @@
+use crate::{sentences, BertEmbeddings, Index, VectorDB};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Sentences further than this from the closest one of a document don't bring their section in
+const CHILD_MARGIN: f32 = 0.1;
+/// Default maximum size of a section in bytes
+pub(crate) const SECTION_SIZE: usize = 2000;
+
+/// Consecutive sentences of a document, what the context gets instead of the whole document
+///
+/// Documents keep their sections with them, the sentences are the small chunks searched by embeddings.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Section {
+    /// Positions in `sentences(text)`
+    pub(crate) sentences: Range<usize>,
+    /// Position in the text, blank lines between sentences included
+    pub(crate) bytes: Range<usize>,
+}
+
+/// Splits `text` in sections of at most `max_size` bytes
+///
+/// A section starts at each markdown heading, or when the next sentence doesn't fit in it anymore.
+/// A sentence longer than `max_size` gets a section of its own.
+pub(crate) fn sections(text: &str, max_size: usize) -> Vec<Section> {
+    let sentences = sentences(text);
+
+    let bytes = |sentence: &str| {
+        let start = sentence.as_ptr() as usize - text.as_ptr() as usize;
+
+        start..start + sentence.len()
+    };
+
+    let mut sections: Vec<Section> = Vec::new();
+
+    for (position, sentence) in sentences.iter().enumerate() {
+        let sentence_bytes = bytes(sentence);
+
+        let starts_section = match sections.last() {
+            None => true,
+            Some(section) => {
+                sentence.trim_start().starts_with('#')
+                    || sentence_bytes.end - section.bytes.start > max_size
+            }
+        };
+
+        if starts_section {
+            sections.push(Section {
+                sentences: position..position + 1,
+                bytes: sentence_bytes,
+            });
+        } else {
+            let section = sections.last_mut().unwrap();
+
+            section.sentences.end = position + 1;
+            section.bytes.end = sentence_bytes.end;
+        }
+    }
+
+    sections
+}
+
+impl VectorDB {
+    /// Sections of a document holding its sentences closest to `query`, in the order of the text
+    ///
+    /// Search runs on sentences, the sections are their parents in the document.
+    /// Several close sentences in the same section only bring it once.
+    ///
+    /// A document without vectors only brings its first section.
+    pub(crate) fn parent_sections(&self, document: usize, query: &BertEmbeddings) -> Vec<&str> {
+        let Some(entry) = self.documents.get(document) else {
+            return Vec::new();
+        };
+
+        let text = &*entry.text;
+        let sections = &entry.sections;
+
+        // sentences without a vector, e.g. after a failed encoding, are never close
+        let mut distances = self.sentence_distances(document, query);
+        distances.resize(
+            sections.last().map_or(0, |section| section.sentences.end),
+            f32::MAX,
+        );
+
+        let closest = distances
+            .iter()
+            .copied()
+            .min_by(|distance1, distance2| distance1.total_cmp(distance2));
+
+        let Some(closest) = closest.filter(|closest| *closest < f32::MAX) else {
+            return sections
+                .first()
+                .map(|section| text[section.bytes.clone()].trim())
+                .into_iter()
+                .collect();
+        };
+
+        sections
+            .iter()
+            .filter(|section| {
+                distances[section.sentences.clone()]
+                    .iter()
+                    .any(|distance| *distance <= closest + CHILD_MARGIN)
+            })
+            .map(|section| text[section.bytes.clone()].trim())
+            .collect()
+    }
+}
+
+impl Index {
+    /// Splits every document again in sections of at most `max_size` bytes, 2000 by default
+    ///
+    /// Sections break at markdown headings, documents added from now on use the same size.
+    /// All the conversations over the index share it, see `RAG::set_parent_sections`.
+    pub fn set_section_size(&self, max_size: usize) {
+        let mut database = self.write();
+
+        database.section_size = max_size;
+
+        for (_, document) in database.documents.iter_mut() {
+            document.sections = sections(&document.text, max_size);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::Candidate;
+use std::collections::HashMap;
+
+/// Decides which documents stay in the context from one turn to the next
+///
+/// Policies only work with document indices and distances, the RAG sorts the context afterwards
+/// and keeps the 5 closest documents.
+pub trait ContextPolicy: Send {
+    /// Merges the search `results` of the new turn into `context`
+    ///
+    /// `context` is sorted from closest to furthest when this is called.
+    /// `results` are (document index, distance), they can be empty when no document was close enough.
+    /// Queries of less than 3 words don't change the context, the policy isn't called for them.
+    fn update(&mut self, context: &mut Vec<Candidate>, results: &[(usize, f32)]);
+}
+
+/// The policy the RAG always had
+///
+/// Old documents get 1.5 times further each turn and are dropped past 0.7.
+/// A document found again keeps the best distance, if the new one isn't better the distance is set
+/// to at least 1.0, which means it will be dropped next turn.
+#[derive(Default)]
+pub struct LegacyDecay;
+
+impl ContextPolicy for LegacyDecay {
+    fn update(&mut self, context: &mut Vec<Candidate>, results: &[(usize, f32)]) {
+        for candicate in context.iter_mut() {
+            candicate.distance *= 1.5;
+        }
+
+        if let Some(too_distant) = context
+            .iter()
+            .position(|candidate| candidate.distance > 0.7)
+        {
+            context.truncate(too_distant);
+        }
+
+        for &(index, distance) in results {
+            if let Some(candidate) = context
+                .iter_mut()
+                .find(|candidate| candidate.index == index)
+            {
+                // we want to favor documents that are relevant multiple rounds
+                if distance < candidate.distance {
+                    candidate.distance = distance;
+                } else {
+                    candidate.distance = (candidate.distance * 0.5).max(1.0);
+                }
+            } else {
+                context.push(Candidate { distance, index })
+            }
+        }
+    }
+}
+
+/// Keeps the documents found during the last `turns` turns with the distance they were last found at
+pub struct SlidingWindow {
+    turns: usize,
+    turn: usize,
+    /// Turn each document was last found at
+    last_seen: HashMap<usize, usize>,
+}
+
+impl SlidingWindow {
+    pub fn new(turns: usize) -> SlidingWindow {
+        SlidingWindow {
+            turns: turns.max(1),
+            turn: 0,
+            last_seen: HashMap::new(),
+        }
+    }
+}
+
+impl ContextPolicy for SlidingWindow {
+    fn update(&mut self, context: &mut Vec<Candidate>, results: &[(usize, f32)]) {
+        self.turn += 1;
+
+        for &(index, distance) in results {
+            self.last_seen.insert(index, self.turn);
+
+            if let Some(candidate) = context
+                .iter_mut()
+                .find(|candidate| candidate.index == index)
+            {
+                candidate.distance = distance;
+            } else {
+                context.push(Candidate { distance, index });
+            }
+        }
+
+        let oldest_turn = self.turn.saturating_sub(self.turns - 1);
+
+        context.retain(|candidate| {
+            self.last_seen
+                .get(&candidate.index)
+                .is_some_and(|&turn| turn >= oldest_turn)
+        });
+        self.last_seen.retain(|_, turn| *turn >= oldest_turn);
+    }
+}
+
+/// Relevance halves every `half_life` turns since the document was last found
+///
+/// Documents are dropped once their distance goes past `max_distance`.
+pub struct ExponentialDecay {
+    half_life: f32,
+    max_distance: f32,
+    turn: usize,
+    /// Distance and turn each document was last found at
+    found: HashMap<usize, (f32, usize)>,
+}
+
+impl ExponentialDecay {
+    pub fn new(half_life: f32, max_distance: f32) -> ExponentialDecay {
+        ExponentialDecay {
+            half_life,
+            max_distance,
+            turn: 0,
+            found: HashMap::new(),
+        }
+    }
+}
+
+impl Default for ExponentialDecay {
+    fn default() -> ExponentialDecay {
+        ExponentialDecay::new(2.0, 0.7)
+    }
+}
+
+impl ContextPolicy for ExponentialDecay {
+    fn update(&mut self, context: &mut Vec<Candidate>, results: &[(usize, f32)]) {
+        self.turn += 1;
+
+        for &(index, distance) in results {
+            self.found.insert(index, (distance, self.turn));
+
+            if !context.iter().any(|candidate| candidate.index == index) {
+                context.push(Candidate { distance, index });
+            }
+        }
+
+        for candidate in context.iter_mut() {
+            let Some(&(distance, turn)) = self.found.get(&candidate.index) else {
+                // the document was put in the context by someone else, we can't age it
+                continue;
+            };
+
+            let age = (self.turn - turn) as f32;
+            let relevance = (1.0 - distance) * 0.5f32.powf(age / self.half_life);
+
+            candidate.distance = 1.0 - relevance;
+        }
+
+        context.retain(|candidate| candidate.distance <= self.max_distance);
+        self.found
+            .retain(|index, _| context.iter().any(|candidate| candidate.index == *index));
+    }
+}
+
+/// Always keeps the `pinned` documents in the context, the others are handled by `inner`
+///
+/// Pinned documents get a distance of 0.0 so they aren't pushed out of the context.
+/// To pin documents during a conversation whatever its policy, use `RAG::pin` instead.
+pub struct Pinned<P> {
+    pinned: Vec<usize>,
+    /// Removed from the context on the next update
+    unpinned: Vec<usize>,
+    inner: P,
+}
+
+impl<P: ContextPolicy> Pinned<P> {
+    pub fn new(pinned: Vec<usize>, inner: P) -> Pinned<P> {
+        Pinned {
+            pinned,
+            unpinned: Vec::new(),
+            inner,
+        }
+    }
+
+    pub fn pin(&mut self, index: usize) {
+        self.unpinned.retain(|&unpinned| unpinned != index);
+
+        if !self.pinned.contains(&index) {
+            self.pinned.push(index);
+        }
+    }
+
+    /// The document leaves the context on the next update, it can come back when a query finds it again
+    pub fn unpin(&mut self, index: usize) {
+        if self.pinned.contains(&index) {
+            self.pinned.retain(|&pinned| pinned != index);
+            self.unpinned.push(index);
+        }
+    }
+
+    pub fn pinned(&self) -> &[usize] {
+        &self.pinned
+    }
+}
+
+impl<P: ContextPolicy> ContextPolicy for Pinned<P> {
+    fn update(&mut self, context: &mut Vec<Candidate>, results: &[(usize, f32)]) {
+        context.retain(|candidate| !self.unpinned.contains(&candidate.index));
+        self.unpinned.clear();
+
+        update_pinned(&self.pinned, &mut self.inner, context, results);
+    }
+}
+
+/// Updates `context` with `policy` as if the `pinned` documents weren't there, then puts them first
+pub(crate) fn update_pinned(
+    pinned: &[usize],
+    policy: &mut dyn ContextPolicy,
+    context: &mut Vec<Candidate>,
+    results: &[(usize, f32)],
+) {
+    context.retain(|candidate| !pinned.contains(&candidate.index));
+
+    let results = results
+        .iter()
+        .filter(|(index, _)| !pinned.contains(index))
+        .copied()
+        .collect::<Vec<_>>();
+
+    policy.update(context, &results);
+
+    for &index in pinned.iter().rev() {
+        context.insert(
+            0,
+            Candidate {
+                distance: 0.0,
+                index,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compares the distances with a tolerance, they go through float multiplications
+    fn assert_context(context: &[Candidate], expected: &[(usize, f32)]) {
+        assert_eq!(context.len(), expected.len(), "{context:?}");
+
+        for (candidate, &(index, distance)) in context.iter().zip(expected) {
+            assert_eq!(candidate.index, index, "{context:?}");
+            assert!((candidate.distance - distance).abs() < 1e-5, "{context:?}");
+        }
+    }
+
+    fn context(candidates: &[(usize, f32)]) -> Vec<Candidate> {
+        candidates
+            .iter()
+            .map(|&(index, distance)| Candidate { distance, index })
+            .collect()
+    }
+
+    #[test]
+    fn legacy_decay_ages_and_drops_documents() {
+        let mut policy = LegacyDecay;
+        let mut context = context(&[(0, 0.2), (1, 0.4), (2, 0.6)]);
+
+        policy.update(&mut context, &[(3, 0.3)]);
+
+        // 0.6 * 1.5 is past 0.7
+        assert_context(&context, &[(0, 0.3), (1, 0.6), (3, 0.3)]);
+    }
+
+    #[test]
+    fn legacy_decay_keeps_the_best_distance() {
+        let mut policy = LegacyDecay;
+        let mut context = context(&[(0, 0.4)]);
+
+        policy.update(&mut context, &[(0, 0.1)]);
+        assert_context(&context, &[(0, 0.1)]);
+
+        // found again further than it is now, it will be dropped next turn
+        policy.update(&mut context, &[(0, 0.5)]);
+        assert_context(&context, &[(0, 1.0)]);
+
+        policy.update(&mut context, &[]);
+        assert!(context.is_empty());
+    }
+
+    #[test]
+    fn sliding_window_forgets_documents_after_its_turns() {
+        let mut policy = SlidingWindow::new(2);
+        let mut context = Vec::new();
+
+        policy.update(&mut context, &[(0, 0.2)]);
+        policy.update(&mut context, &[(1, 0.3)]);
+        assert_context(&context, &[(0, 0.2), (1, 0.3)]);
+
+        policy.update(&mut context, &[(1, 0.1)]);
+        assert_context(&context, &[(1, 0.1)]);
+
+        policy.update(&mut context, &[]);
+        policy.update(&mut context, &[]);
+        assert!(context.is_empty());
+    }
+
+    #[test]
+    fn exponential_decay_halves_relevance_every_half_life() {
+        let mut policy = ExponentialDecay::new(1.0, 0.7);
+        let mut context = Vec::new();
+
+        policy.update(&mut context, &[(0, 0.2)]);
+        assert_context(&context, &[(0, 0.2)]);
+
+        // relevance 0.8 becomes 0.4
+        policy.update(&mut context, &[]);
+        assert_context(&context, &[(0, 0.6)]);
+
+        // then 0.2, too far
+        policy.update(&mut context, &[]);
+        assert!(context.is_empty());
+    }
+
+    #[test]
+    fn exponential_decay_keeps_documents_it_did_not_add() {
+        let mut policy = ExponentialDecay::default();
+        let mut context = context(&[(0, 0.5)]);
+
+        policy.update(&mut context, &[]);
+
+        assert_context(&context, &[(0, 0.5)]);
+    }
+
+    #[test]
+    fn pinned_documents_stay_first() {
+        let mut policy = Pinned::new(vec![4, 5], LegacyDecay);
+        let mut context = Vec::new();
+
+        policy.update(&mut context, &[(0, 0.3), (4, 0.6)]);
+        assert_context(&context, &[(4, 0.0), (5, 0.0), (0, 0.3)]);
+
+        policy.unpin(5);
+        policy.pin(1);
+        policy.update(&mut context, &[]);
+
+        // 0.3 * 1.5 is still close enough, the unpinned document left the context
+        assert_context(&context, &[(4, 0.0), (1, 0.0), (0, 0.45)]);
+        assert_eq!(policy.pinned(), &[4, 1]);
+
+        // it comes back with its distance when a query finds it again
+        policy.unpin(4);
+        policy.update(&mut context, &[(4, 0.2)]);
+        assert_context(&context, &[(1, 0.0), (0, 0.675), (4, 0.2)]);
+    }
+}
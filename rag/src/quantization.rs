@@ -0,0 +1,540 @@
+use crate::{
+    segment::{Bytes, Rows},
+    SentenceKey,
+};
+use instant_distance::{HnswMap, Point, Search};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{cell::RefCell, collections::HashSet};
+
+/// How the vectors of the embedding index are stored in memory
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Quantization {
+    /// 4 bytes per dimension
+    #[default]
+    None,
+    /// 1 byte per dimension, scaled by the largest value of each vector
+    Int8,
+    /// 1 bit per dimension, only the sign of each value is kept
+    Binary,
+}
+
+impl Quantization {
+    /// Bytes of `vector` as stored in the index
+    fn encode(self, vector: &[f32]) -> Vec<u8> {
+        match self {
+            Quantization::None => vector
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+            Quantization::Int8 => {
+                let max = vector
+                    .iter()
+                    .fold(0.0f32, |max, value| max.max(value.abs()));
+                let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+
+                scale
+                    .to_le_bytes()
+                    .into_iter()
+                    .chain(
+                        vector
+                            .iter()
+                            .map(|value| (value / scale).round() as i8 as u8),
+                    )
+                    .collect()
+            }
+            Quantization::Binary => {
+                let mut bits = vec![0u64; vector.len().div_ceil(64)];
+
+                for (i, value) in vector.iter().enumerate() {
+                    if *value > 0.0 {
+                        bits[i / 64] |= 1 << (i % 64);
+                    }
+                }
+
+                (vector.len() as u32)
+                    .to_le_bytes()
+                    .into_iter()
+                    .chain(bits.iter().flat_map(|bits| bits.to_le_bytes()))
+                    .collect()
+            }
+        }
+    }
+
+    /// Vector of `bytes`, with the precision lost by quantizing
+    pub(crate) fn decode(self, bytes: &[u8]) -> Vec<f32> {
+        match self {
+            Quantization::None => floats(bytes).collect(),
+            Quantization::Int8 => {
+                let scale = floats(&bytes[..4]).next().unwrap();
+
+                bytes[4..]
+                    .iter()
+                    .map(|&value| value as i8 as f32 * scale)
+                    .collect()
+            }
+            Quantization::Binary => {
+                let dimensions = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+                let value = 1.0 / (dimensions.max(1) as f32).sqrt();
+                let bits = words(&bytes[4..]).collect::<Vec<_>>();
+
+                (0..dimensions)
+                    .map(|i| {
+                        if bits[i / 64] & (1 << (i % 64)) != 0 {
+                            value
+                        } else {
+                            -value
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn distance(self, a: &[u8], b: &[u8]) -> f32 {
+        assert_eq!(a.len(), b.len());
+
+        match self {
+            Quantization::None => {
+                // For roberta we can use the dot product
+                1.0 - floats(a).zip(floats(b)).map(|(a, b)| a * b).sum::<f32>()
+            }
+            Quantization::Int8 => {
+                let scale_a = floats(&a[..4]).next().unwrap();
+                let scale_b = floats(&b[..4]).next().unwrap();
+
+                let dot_product = a[4..]
+                    .iter()
+                    .zip(&b[4..])
+                    .map(|(&a, &b)| a as i8 as i32 * b as i8 as i32)
+                    .sum::<i32>();
+
+                1.0 - dot_product as f32 * scale_a * scale_b
+            }
+            Quantization::Binary => {
+                let dimensions = u32::from_le_bytes(a[..4].try_into().unwrap());
+
+                let hamming = words(&a[4..])
+                    .zip(words(&b[4..]))
+                    .map(|(a, b)| (a ^ b).count_ones())
+                    .sum::<u32>();
+
+                // the share of differing signs roughly gives the angle between the vectors
+                // so the distance stays comparable to `1 - dot product` of the full vectors
+                let angle = std::f32::consts::PI * hamming as f32 / dimensions.max(1) as f32;
+
+                1.0 - angle.cos()
+            }
+        }
+    }
+}
+
+fn floats(bytes: &[u8]) -> impl Iterator<Item = f32> + '_ {
+    bytes
+        .chunks_exact(std::mem::size_of::<f32>())
+        .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+}
+
+fn words(bytes: &[u8]) -> impl Iterator<Item = u64> + '_ {
+    bytes
+        .chunks_exact(std::mem::size_of::<u64>())
+        .map(|value| u64::from_le_bytes(value.try_into().unwrap()))
+}
+
+thread_local! {
+    /// Rows the vectors of an index are read from while it's decoded, see `VectorIndex::decode`
+    static DECODED_ROWS: RefCell<Option<(Rows, Quantization)>> = const { RefCell::new(None) };
+}
+
+/// A vector of the index, its bytes are a row of the vectors segment or of the rows added since
+///
+/// Only the row is saved with the graph, the vectors themselves stay memory-mapped.
+#[derive(Clone)]
+pub(crate) struct Vector {
+    row: usize,
+    bytes: Bytes,
+    quantization: Quantization,
+}
+
+impl Vector {
+    /// A vector that isn't in the index, e.g. a query
+    fn query(quantization: Quantization, vector: &[f32]) -> Vector {
+        Vector {
+            row: usize::MAX,
+            bytes: Bytes::Owned(quantization.encode(vector).into()),
+            quantization,
+        }
+    }
+}
+
+impl Point for Vector {
+    fn distance(&self, other: &Vector) -> f32 {
+        self.quantization
+            .distance(self.bytes.as_ref(), other.bytes.as_ref())
+    }
+}
+
+impl Serialize for Vector {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.row as u64)
+    }
+}
+
+impl<'de> Deserialize<'de> for Vector {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Vector, D::Error> {
+        let row = u64::deserialize(deserializer)? as usize;
+
+        DECODED_ROWS.with(|rows| {
+            let rows = rows.borrow();
+            let (rows, quantization) = rows
+                .as_ref()
+                .ok_or_else(|| D::Error::custom("vectors decoded without their rows"))?;
+
+            Ok(Vector {
+                row,
+                bytes: rows
+                    .get(row)
+                    .ok_or_else(|| D::Error::custom("vector without a row"))?,
+                quantization: *quantization,
+            })
+        })
+    }
+}
+
+/// HNSW graph over the sentence embeddings, in the precision chosen with `Quantization`
+pub(crate) struct VectorIndex {
+    quantization: Quantization,
+    map: HnswMap<Vector, SentenceKey>,
+}
+
+impl Serialize for VectorIndex {
+    /// The quantization is saved in the manifest, see `VectorIndex::decode`
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.map.serialize(serializer)
+    }
+}
+
+impl VectorIndex {
+    pub(crate) fn new(quantization: Quantization) -> VectorIndex {
+        VectorIndex::build(quantization, &mut Rows::default(), Vec::new())
+    }
+
+    /// Appends the vectors to `rows`, usually empty, the keys point to their new row
+    pub(crate) fn build(
+        quantization: Quantization,
+        rows: &mut Rows,
+        vectors: Vec<(Vec<f32>, SentenceKey)>,
+    ) -> VectorIndex {
+        let (points, keys) = push_rows(quantization, rows, vectors).unzip();
+
+        VectorIndex {
+            quantization,
+            map: instant_distance::Builder::default().build(points, keys),
+        }
+    }
+
+    /// Graph saved with `bincode`, its vectors are read from `rows`
+    pub(crate) fn decode(bytes: &[u8], rows: &Rows, quantization: Quantization) -> VectorIndex {
+        DECODED_ROWS.with(|decoded| *decoded.borrow_mut() = Some((rows.clone(), quantization)));
+        let map = bincode::deserialize(bytes);
+        DECODED_ROWS.with(|decoded| *decoded.borrow_mut() = None);
+
+        VectorIndex {
+            quantization,
+            map: map.unwrap(),
+        }
+    }
+
+    pub(crate) fn quantization(&self) -> Quantization {
+        self.quantization
+    }
+
+    /// Every vector of the index with its key, dequantized
+    pub(crate) fn vectors(&self) -> Vec<(Vec<f32>, SentenceKey)> {
+        self.map
+            .iter()
+            .map(|(point_id, point)| {
+                (
+                    self.quantization.decode(point.bytes.as_ref()),
+                    self.map.values[point_id.into_inner() as usize],
+                )
+            })
+            .collect()
+    }
+
+    /// Appends the vectors to `rows` and rebuilds the graph, existing points are kept as they are
+    pub(crate) fn add(&mut self, rows: &mut Rows, vectors: Vec<(Vec<f32>, SentenceKey)>) {
+        let (points, values) = self
+            .points()
+            .chain(push_rows(self.quantization, rows, vectors))
+            .unzip();
+
+        self.map = instant_distance::Builder::default().build(points, values);
+    }
+
+    /// Rows of the removed points stay in the segment until the index is rebuilt, see `Index::compact`
+    pub(crate) fn retain(&mut self, keep: impl Fn(&SentenceKey) -> bool) {
+        let (points, values) = self.points().filter(|(_, key)| keep(key)).unzip();
+
+        self.map = instant_distance::Builder::default().build(points, values);
+    }
+
+    fn points(&self) -> impl Iterator<Item = (Vector, SentenceKey)> + '_ {
+        self.map.iter().map(|(point_id, point)| {
+            (
+                point.clone(),
+                self.map.values[point_id.into_inner() as usize],
+            )
+        })
+    }
+
+    pub(crate) fn keys(&self) -> &[SentenceKey] {
+        &self.map.values
+    }
+
+    /// Changing a key doesn't change the graph, only which sentence a point refers to
+    pub(crate) fn keys_mut(&mut self) -> &mut [SentenceKey] {
+        &mut self.map.values
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.map.values.len()
+    }
+
+    /// Closest sentences to `query` until `documents` different documents are found, from closest to furthest
+    pub(crate) fn search(&self, query: &[f32], documents: usize) -> Vec<(SentenceKey, f32)> {
+        let query = Vector::query(self.quantization, query);
+
+        let mut results = Vec::new();
+        let mut found = HashSet::new();
+
+        let mut search = Search::default();
+        for item in self.map.search(&query, &mut search) {
+            if !found.contains(&item.value.document) {
+                if found.len() == documents {
+                    break;
+                }
+
+                found.insert(item.value.document);
+            }
+
+            results.push((*item.value, item.distance));
+        }
+
+        results
+    }
+
+    /// Bytes used by the vectors, mapped or in memory, the graph itself isn't counted
+    pub(crate) fn memory(&self) -> usize {
+        self.map
+            .iter()
+            .map(|(_, point)| point.bytes.as_ref().len())
+            .sum()
+    }
+}
+
+/// Vectors of the index for `vectors`, each one in a new row of `rows` that its key points to
+fn push_rows(
+    quantization: Quantization,
+    rows: &mut Rows,
+    vectors: Vec<(Vec<f32>, SentenceKey)>,
+) -> impl Iterator<Item = (Vector, SentenceKey)> + '_ {
+    vectors.into_iter().map(move |(vector, mut key)| {
+        let row = rows.push(quantization.encode(&vector));
+        key.row = row;
+
+        let point = Vector {
+            row,
+            bytes: rows.get(row).unwrap(),
+            quantization,
+        };
+
+        (point, key)
+    })
+}
+
+/// Full precision vectors used to rescore the results of a quantized index
+///
+/// Each save writes them to the segment of its generation, older generations and backups keep theirs.
+#[derive(Default)]
+pub(crate) struct FullVectors {
+    rows: Rows,
+}
+
+impl FullVectors {
+    pub(crate) fn new() -> FullVectors {
+        FullVectors::default()
+    }
+
+    pub(crate) fn mapped(rows: Rows) -> FullVectors {
+        FullVectors { rows }
+    }
+
+    pub(crate) fn rows(&self) -> &Rows {
+        &self.rows
+    }
+
+    pub(crate) fn dimensions(&self) -> usize {
+        self.rows.size() / std::mem::size_of::<f32>()
+    }
+
+    /// Returns the row of the first vector, the others follow
+    pub(crate) fn extend(&mut self, vectors: &[Vec<f32>]) -> usize {
+        let first_row = self.rows.len();
+
+        for vector in vectors {
+            self.rows.push(
+                vector
+                    .iter()
+                    .flat_map(|value| value.to_le_bytes())
+                    .collect(),
+            );
+        }
+
+        first_row
+    }
+
+    pub(crate) fn get(&self, rows: &[usize]) -> Vec<Vec<f32>> {
+        rows.iter()
+            .map(|&row| {
+                self.rows
+                    .get(row)
+                    .unwrap()
+                    .as_ref()
+                    .chunks_exact(std::mem::size_of::<f32>())
+                    .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(vector: &[f32]) -> Vec<f32> {
+        let magnitude = vector.iter().map(|v| v.powi(2)).sum::<f32>().sqrt();
+
+        vector.iter().map(|value| value / magnitude).collect()
+    }
+
+    /// The query, then vectors from closest to furthest
+    fn vectors() -> Vec<Vec<f32>> {
+        [
+            [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0],
+            [1.0, 0.9, 1.0, 0.8, 1.0, 1.0, -0.3, 1.0],
+            [1.0, -1.0, 1.0, -1.0, 1.0, -1.0, 1.0, -1.0],
+            [-1.0, -1.0, -0.5, -1.0, -1.0, -1.0, -1.0, -1.0],
+        ]
+        .iter()
+        .map(|vector| normalized(vector))
+        .collect()
+    }
+
+    #[test]
+    fn int8_vectors_keep_most_of_their_precision() {
+        for vector in vectors() {
+            let bytes = Quantization::Int8.encode(&vector);
+            assert_eq!(bytes.len(), 4 + vector.len());
+
+            let max = vector
+                .iter()
+                .fold(0.0f32, |max, value| max.max(value.abs()));
+            for (decoded, value) in Quantization::Int8.decode(&bytes).iter().zip(&vector) {
+                assert!((decoded - value).abs() <= max / 127.0);
+            }
+        }
+    }
+
+    #[test]
+    fn binary_vectors_keep_the_signs() {
+        for vector in vectors() {
+            let bytes = Quantization::Binary.encode(&vector);
+            assert_eq!(bytes.len(), 4 + 8);
+
+            let decoded = Quantization::Binary.decode(&bytes);
+            assert_eq!(decoded.len(), vector.len());
+            for (decoded, value) in decoded.iter().zip(&vector) {
+                assert_eq!(decoded.is_sign_positive(), *value > 0.0);
+            }
+
+            // still normalized
+            let magnitude = decoded.iter().map(|v| v.powi(2)).sum::<f32>().sqrt();
+            assert!((magnitude - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn distances_keep_their_order_once_quantized() {
+        let vectors = vectors();
+        let query = &vectors[0];
+
+        for quantization in [Quantization::None, Quantization::Int8, Quantization::Binary] {
+            let encoded_query = quantization.encode(query);
+            let distances = vectors
+                .iter()
+                .map(|vector| quantization.distance(&encoded_query, &quantization.encode(vector)))
+                .collect::<Vec<_>>();
+
+            assert!(distances[0].abs() < 0.01, "{quantization:?} {distances:?}");
+            assert!(
+                distances.windows(2).all(|pair| pair[0] < pair[1]),
+                "{quantization:?} {distances:?}"
+            );
+        }
+
+        // int8 distances are close to the full precision ones
+        let encoded_query = Quantization::Int8.encode(query);
+        for vector in &vectors {
+            let distance = 1.0 - query.iter().zip(vector).map(|(a, b)| a * b).sum::<f32>();
+            let int8_distance =
+                Quantization::Int8.distance(&encoded_query, &Quantization::Int8.encode(vector));
+
+            assert!((distance - int8_distance).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn quantized_indices_find_the_closest_documents() {
+        let vectors = vectors();
+
+        for quantization in [Quantization::None, Quantization::Int8, Quantization::Binary] {
+            let mut rows = Rows::default();
+            let index = VectorIndex::build(
+                quantization,
+                &mut rows,
+                vectors[1..]
+                    .iter()
+                    .enumerate()
+                    .map(|(document, vector)| {
+                        (
+                            vector.clone(),
+                            SentenceKey {
+                                document,
+                                sentence: 0,
+                                row: 0,
+                            },
+                        )
+                    })
+                    .collect(),
+            );
+
+            assert_eq!(rows.len(), 3);
+            assert_eq!(index.memory(), 3 * quantization.encode(&vectors[0]).len());
+
+            let documents = index
+                .search(&vectors[0], 2)
+                .into_iter()
+                .map(|(key, _)| key.document)
+                .collect::<Vec<_>>();
+            assert_eq!(documents, [0, 1], "{quantization:?}");
+
+            // the vectors are read back from their rows
+            for (vector, key) in index.vectors() {
+                let expected = &vectors[key.document + 1];
+                assert_eq!(key.row, key.document);
+                assert_eq!(vector, quantization.decode(&quantization.encode(expected)));
+            }
+        }
+    }
+}
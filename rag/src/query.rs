@@ -0,0 +1,240 @@
+use crate::TextGenerator;
+use shared::{Speaker, END_OF_SENTENCE, SPLIT_WORD};
+use std::fmt::Write;
+
+/// How many of the last history entries are used to rewrite a query
+const REWRITE_TURNS: usize = 4;
+/// A query with this many keywords is considered standalone
+const STANDALONE_KEYWORDS: usize = 4;
+/// Maximum number of keywords in a rewritten query
+const MAX_KEYWORDS: usize = 8;
+
+pub(crate) const STOP_WORDS: &[&str] = &[
+    "all", "and", "any", "are", "but", "can", "did", "for", "had", "has", "her", "him", "his",
+    "how", "its", "not", "now", "one", "our", "she", "the", "too", "was", "who", "why", "yes",
+    "you", "about", "after", "again", "also", "because", "been", "before", "being", "could",
+    "does", "doing", "from", "have", "having", "here", "into", "just", "like", "more", "most",
+    "only", "other", "should", "some", "such", "than", "that", "their", "them", "then", "there",
+    "these", "they", "this", "those", "very", "want", "what", "when", "where", "which", "while",
+    "will", "with", "would", "your", "yours",
+];
+
+/// Turns the latest message into a standalone search query using the previous turns
+///
+/// Follow-ups like "and the blue one?" or "yes" don't retrieve anything on their own.
+pub enum QueryRewriter<'a> {
+    /// Carries over keywords from the previous turns
+    Keywords,
+    /// Asks the LLM to rewrite the query
+    Generator(&'a dyn TextGenerator),
+}
+
+impl QueryRewriter<'_> {
+    /// `history` is ordered from oldest to newest and doesn't contain `query`
+    pub fn rewrite(&self, query: &str, history: &[(Speaker, &str)]) -> String {
+        let history = &history[history.len().saturating_sub(REWRITE_TURNS)..];
+
+        if history.is_empty() {
+            return query.to_string();
+        }
+
+        match self {
+            QueryRewriter::Keywords => {
+                let mut query_keywords = keywords(query);
+
+                if query_keywords.len() >= STANDALONE_KEYWORDS {
+                    return query.to_string();
+                }
+
+                let mut rewritten = query.to_string();
+
+                // the most recent turns are the most likely to be referenced
+                for (_, text) in history.iter().rev() {
+                    for keyword in keywords(text) {
+                        if query_keywords.len() == MAX_KEYWORDS {
+                            return rewritten;
+                        }
+
+                        if !query_keywords.contains(&keyword) {
+                            rewritten.push(' ');
+                            rewritten.push_str(&keyword);
+                            query_keywords.push(keyword);
+                        }
+                    }
+                }
+
+                rewritten
+            }
+            QueryRewriter::Generator(generator) => {
+                let mut conversation = String::new();
+                for (speaker, text) in history {
+                    let speaker = match speaker {
+                        Speaker::User => "User",
+                        Speaker::Assistant => "Assistant",
+                    };
+
+                    writeln!(conversation, "{speaker}: {text}").unwrap();
+                }
+
+                let prompt = format!(
+                    "Rewrite the last message of the conversation as a standalone search query. \
+                    Include the names and details it refers to. \
+                    Only reply with the query.\n\n\
+                    {conversation}\
+                    User: {query}"
+                );
+
+                let rewritten = generator.generate(&prompt, 40);
+                let rewritten = rewritten.lines().next().unwrap_or_default().trim();
+
+                if rewritten.is_empty() {
+                    query.to_string()
+                } else {
+                    rewritten.trim_matches('"').to_string()
+                }
+            }
+        }
+    }
+}
+
+fn keywords(text: &str) -> Vec<String> {
+    let mut keywords: Vec<String> = Vec::new();
+
+    for word in text.split(|c| END_OF_SENTENCE.contains(&c) || SPLIT_WORD.contains(&c)) {
+        let word = word
+            .trim_matches(|c: char| !c.is_alphanumeric())
+            .to_lowercase();
+
+        if word.chars().count() > 2
+            && !STOP_WORDS.contains(&word.as_str())
+            && !keywords.contains(&word)
+        {
+            keywords.push(word);
+        }
+    }
+
+    keywords
+}
+
+/// How the query is turned into searches
+pub enum RetrievalMode<'a> {
+    /// Searches the query as is
+    Direct,
+    /// Hypothetical Document Embeddings
+    ///
+    /// The LLM writes a hypothetical answer and we search for passages close to it.
+    /// Short questions are far from long manual chunks, a made up answer is a lot closer.
+    HyDE(&'a dyn TextGenerator),
+    /// The LLM writes `count` paraphrases of the query, each is searched and results are fused
+    MultiQuery(&'a dyn TextGenerator, usize),
+    /// The BM25 query gets the entities most often mentioned with the ones of the query
+    ///
+    /// e.g. a query about "Simon Says" also matches passages about its colors.
+    Entities,
+}
+
+impl RetrievalMode<'_> {
+    /// Returns the (BM25 query, embeddings query) pairs to search
+    ///
+    /// `expand_entities` adds the related entities to a query, the graph is in the database.
+    pub(crate) fn expand(
+        &self,
+        query: &str,
+        expand_entities: impl FnOnce(&str) -> String,
+    ) -> Vec<(String, String)> {
+        match self {
+            RetrievalMode::Direct => vec![(query.to_string(), query.to_string())],
+            RetrievalMode::Entities => vec![(expand_entities(query), query.to_string())],
+            RetrievalMode::HyDE(generator) => {
+                let prompt = format!(
+                    "Write a short passage from a manual that answers the question below. \
+                    Only reply with the passage.\n\n\
+                    Question: {query}"
+                );
+
+                let answer = generator.generate(&prompt, 150);
+
+                if answer.trim().is_empty() {
+                    return vec![(query.to_string(), query.to_string())];
+                }
+
+                // bm25 would match the made up words, it only gets the real query
+                vec![(query.to_string(), answer)]
+            }
+            RetrievalMode::MultiQuery(generator, count) => {
+                let prompt = format!(
+                    "Write {count} different versions of the search query below, \
+                    using other words when possible. \
+                    Write one version per line and only reply with the versions.\n\n\
+                    Query: {query}"
+                );
+
+                let paraphrases = generator.generate(&prompt, 30 * *count as u32);
+
+                let mut queries = vec![(query.to_string(), query.to_string())];
+
+                for paraphrase in paraphrases.lines() {
+                    if queries.len() > *count {
+                        break;
+                    }
+
+                    // remove list markers, e.g. "1." or "-"
+                    let paraphrase = paraphrase
+                        .trim_start_matches(|c: char| {
+                            c.is_ascii_digit() || c == '.' || c == ')' || c == '-' || c == '*'
+                        })
+                        .trim()
+                        .trim_matches('"');
+
+                    if !paraphrase.is_empty()
+                        && !queries
+                            .iter()
+                            .any(|(existing, _)| existing.eq_ignore_ascii_case(paraphrase))
+                    {
+                        queries.push((paraphrase.to_string(), paraphrase.to_string()));
+                    }
+                }
+
+                queries
+            }
+        }
+    }
+}
+
+/// Merges multiple ranked lists, documents ranked high in many lists come first
+///
+/// The distance of each document is the best distance it got.
+pub(crate) fn reciprocal_rank_fusion(
+    results: Vec<Vec<(usize, f32)>>,
+    top_k: usize,
+) -> Vec<(usize, f32)> {
+    /// Dampens the weight of the first ranks, 60 is the value from the paper
+    const K: f32 = 60.0;
+
+    // (index, fused score, best distance)
+    let mut fused: Vec<(usize, f32, f32)> = Vec::new();
+
+    for ranked in results {
+        for (rank, (index, distance)) in ranked.into_iter().enumerate() {
+            let score = 1.0 / (K + rank as f32 + 1.0);
+
+            if let Some((_, fused_score, best_distance)) = fused
+                .iter_mut()
+                .find(|(fused_index, _, _)| *fused_index == index)
+            {
+                *fused_score += score;
+                *best_distance = best_distance.min(distance);
+            } else {
+                fused.push((index, score, distance));
+            }
+        }
+    }
+
+    fused.sort_unstable_by(|(_, score1, _), (_, score2, _)| score2.total_cmp(score1));
+    fused.truncate(top_k);
+
+    fused
+        .into_iter()
+        .map(|(index, _, distance)| (index, distance))
+        .collect()
+}
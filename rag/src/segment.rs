@@ -0,0 +1,773 @@
+use crate::{
+    parent::Section,
+    quantization::{FullVectors, Quantization, VectorIndex},
+    BertEmbeddings, Document, Language, Metadata, VectorDB, WordCount,
+};
+use fst::Streamer;
+use instant_distance::HnswMap;
+use memmap2::Mmap;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use slab::Slab;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    ops::{Deref, DerefMut, Range},
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Start of every manifest, followed by `FORMAT_VERSION`
+const MAGIC: &[u8; 4] = b"RAGD";
+/// Bincode isn't self-describing, any change to the manifest or a segment needs a new version
+const FORMAT_VERSION: u32 = 1;
+
+const TEXTS: &str = "texts";
+const WORD_COUNTS: &str = "fst";
+const INDEX: &str = "hnsw";
+const VECTORS: &str = "vectors";
+const DOCUMENTS: &str = "documents";
+const HASHES: &str = "hashes";
+const GRAPH: &str = "graph";
+const FULL_VECTORS: &str = "full";
+const SEGMENTS: [&str; 8] = [
+    TEXTS,
+    INDEX,
+    WORD_COUNTS,
+    VECTORS,
+    DOCUMENTS,
+    HASHES,
+    GRAPH,
+    FULL_VECTORS,
+];
+/// Copy of a previous manifest, its segments are kept with it
+const BACKUP: &str = "backup";
+/// Previous saves kept by default
+pub(crate) const BACKUPS: usize = 2;
+
+/// Bytes owned or read from a memory-mapped segment file, cloning them doesn't copy them
+#[derive(Clone)]
+pub(crate) enum Bytes {
+    Owned(Arc<[u8]>),
+    Mapped {
+        segment: Arc<Mmap>,
+        range: Range<usize>,
+    },
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Bytes::Owned(bytes) => bytes,
+            Bytes::Mapped { segment, range } => &segment[range.clone()],
+        }
+    }
+}
+
+/// Text of a document, pages of the segment are only read by the OS when it's used
+///
+/// Always valid UTF-8: built from a `String` or from a range of the texts segment checked by `VectorDB::load`.
+pub(crate) struct Text(Bytes);
+
+impl From<String> for Text {
+    fn from(text: String) -> Text {
+        Text(Bytes::Owned(text.into_bytes().into()))
+    }
+}
+
+impl Deref for Text {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        // SAFETY: the bytes come from a `String`, or from the texts segment that `VectorDB::load`
+        // checked is UTF-8 with each range starting and ending on a char boundary
+        unsafe { std::str::from_utf8_unchecked(self.0.as_ref()) }
+    }
+}
+
+/// Rows of the same size one after the other, e.g. vectors
+///
+/// The rows of the saved generation are memory-mapped, the ones added since are kept in memory
+/// until the next save writes them all to the segment of the new generation.
+#[derive(Clone, Default)]
+pub(crate) struct Rows {
+    /// Bytes per row, 0 until the first one is added
+    size: usize,
+    saved: Option<Bytes>,
+    added: Vec<Bytes>,
+}
+
+impl Rows {
+    fn mapped(segment: Bytes, size: usize) -> Rows {
+        Rows {
+            size,
+            saved: Some(segment),
+            added: Vec::new(),
+        }
+    }
+
+    fn saved_rows(&self) -> usize {
+        match &self.saved {
+            Some(saved) if self.size > 0 => saved.as_ref().len() / self.size,
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.saved_rows() + self.added.len()
+    }
+
+    /// Bytes per row
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of the new row, all rows must have the same size
+    pub(crate) fn push(&mut self, row: Vec<u8>) -> usize {
+        if self.len() == 0 {
+            self.size = row.len();
+        }
+        assert_eq!(row.len(), self.size);
+
+        self.added.push(Bytes::Owned(row.into()));
+
+        self.len() - 1
+    }
+
+    pub(crate) fn get(&self, row: usize) -> Option<Bytes> {
+        let saved_rows = self.saved_rows();
+
+        if row < saved_rows {
+            let Bytes::Mapped { segment, range } = self.saved.as_ref()? else {
+                unreachable!("saved rows are mapped")
+            };
+
+            let start = range.start + row * self.size;
+
+            Some(Bytes::Mapped {
+                segment: segment.clone(),
+                range: start..start + self.size,
+            })
+        } else {
+            self.added.get(row - saved_rows).cloned()
+        }
+    }
+
+    fn write(&self, mut writer: impl Write) {
+        if let Some(saved) = &self.saved {
+            writer.write_all(saved.as_ref()).unwrap();
+        }
+
+        for row in &self.added {
+            writer.write_all(row.as_ref()).unwrap();
+        }
+    }
+}
+
+type Decode<T> = Box<dyn Fn(&[u8]) -> T + Send + Sync>;
+
+/// Decoded from its segment the first time it's used
+pub(crate) struct Lazy<T> {
+    value: OnceLock<T>,
+    segment: Option<(Bytes, Decode<T>)>,
+}
+
+impl<T> Lazy<T> {
+    pub(crate) fn new(value: T) -> Lazy<T> {
+        Lazy {
+            value: OnceLock::from(value),
+            segment: None,
+        }
+    }
+
+    fn mapped(segment: Bytes) -> Lazy<T>
+    where
+        T: DeserializeOwned,
+    {
+        Lazy::mapped_with(segment, |bytes| bincode::deserialize(bytes).unwrap())
+    }
+
+    /// `decode` turns the segment into the value, e.g. when it points into other segments
+    fn mapped_with(segment: Bytes, decode: impl Fn(&[u8]) -> T + Send + Sync + 'static) -> Lazy<T> {
+        Lazy {
+            value: OnceLock::new(),
+            segment: Some((segment, Box::new(decode))),
+        }
+    }
+
+    /// The segment is copied as is when it was never decoded
+    fn write(&self, mut writer: impl Write)
+    where
+        T: Serialize,
+    {
+        match (self.value.get(), &self.segment) {
+            (Some(value), _) => bincode::serialize_into(writer, value).unwrap(),
+            (None, Some((segment, _))) => writer.write_all(segment.as_ref()).unwrap(),
+            (None, None) => unreachable!("a lazy value has either a value or a segment"),
+        }
+    }
+}
+
+impl<T> Deref for Lazy<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.get_or_init(|| {
+            let (segment, decode) = self.segment.as_ref().unwrap();
+
+            decode(segment.as_ref())
+        })
+    }
+}
+
+impl<T> DerefMut for Lazy<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        let _ = self.deref();
+
+        self.value.get_mut().unwrap()
+    }
+}
+
+impl<T: Default> Default for Lazy<T> {
+    fn default() -> Lazy<T> {
+        Lazy::new(T::default())
+    }
+}
+
+/// Why a saved database can't be opened
+#[derive(Debug)]
+pub enum OpenError {
+    Io(std::io::Error),
+    /// Not a database, or saved in a format that can't be migrated
+    UnknownFormat,
+    /// Saved by a newer version of this crate
+    UnsupportedVersion(u32),
+    /// The manifest can't be decoded
+    Corrupted(bincode::Error),
+    /// Encoded with `saved`, a model that isn't downloaded automatically, it has to be given to open the database
+    UnknownModel(String),
+    /// Encoded with `saved` and opened with `embedder`, see `Index::reencode_at`
+    ModelMismatch {
+        saved: String,
+        embedder: String,
+    },
+}
+
+impl Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenError::Io(error) => write!(f, "{error}"),
+            OpenError::UnknownFormat => write!(f, "not a database or an unknown format"),
+            OpenError::UnsupportedVersion(version) => write!(
+                f,
+                "saved in format {version}, this version only reads format {FORMAT_VERSION}"
+            ),
+            OpenError::Corrupted(error) => write!(f, "corrupted manifest: {error}"),
+            OpenError::UnknownModel(model) => {
+                write!(f, "encoded with {model}, open it with the embedder of this model")
+            }
+            OpenError::ModelMismatch { saved, embedder } => write!(
+                f,
+                "encoded with {saved} and opened with {embedder}, encode it again to change its model"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+impl From<std::io::Error> for OpenError {
+    fn from(error: std::io::Error) -> OpenError {
+        OpenError::Io(error)
+    }
+}
+
+impl From<bincode::Error> for OpenError {
+    fn from(error: bincode::Error) -> OpenError {
+        OpenError::Corrupted(error)
+    }
+}
+
+/// What is read when the database is opened, its size doesn't depend on the number of documents
+///
+/// Document entries, hashes and the entity graph are in their own segments, decoded when first used.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    /// Embedding model of the vectors, see `saved_model`
+    model: String,
+    /// Segment files are named after the generation, each save writes new ones
+    generation: u64,
+    /// Position of the word count of the whole database in the fst segment
+    total_word_count: Range<usize>,
+    average_word_count: f32,
+    quantization: Quantization,
+    /// Bytes per row of the vectors segment
+    vector_size: usize,
+    /// Dimensions of the full precision vectors kept for rescoring, in their own segment
+    full_vectors: Option<usize>,
+    /// Maximum size of the sections of the documents
+    section_size: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DocumentEntry {
+    metadata: Metadata,
+    word_count: u64,
+    language: Language,
+    /// Position in the texts segment
+    text: Range<usize>,
+    /// Position in the fst segment
+    individual_word_count: Range<usize>,
+    /// Rows of its vectors in the vectors segment
+    rows: Range<usize>,
+    sections: Vec<Section>,
+}
+
+/// Database saved in a single file by the first version of the crate, before the header existed
+#[derive(Deserialize)]
+struct LegacyDatabase {
+    _map: HnswMap<BertEmbeddings, usize>,
+    documents: Slab<LegacyDocument>,
+    _total_word_count: HashMap<String, u64>,
+    _average_word_count: f32,
+    _file_hashes: HashMap<String, usize>,
+}
+
+#[derive(Deserialize)]
+struct LegacyDocument {
+    text: String,
+    _individual_word_count: Vec<u8>,
+    _word_count: u64,
+}
+
+/// Texts of the documents of a database saved by the first version of the crate, `None` if it isn't one
+///
+/// Its vectors aren't kept, they were computed on sentences split differently and without their position.
+pub(crate) fn legacy_texts(path: &Path) -> Option<Vec<String>> {
+    let file = File::open(path).ok()?;
+    let database: LegacyDatabase = bincode::deserialize_from(BufReader::new(file)).ok()?;
+
+    Some(
+        database
+            .documents
+            .into_iter()
+            .map(|(_, document)| document.text)
+            .collect(),
+    )
+}
+
+/// `./resources/database.data` has its segments in `./resources/database.<generation>.texts` etc.
+fn segment_path(path: &Path, generation: u64, segment: &str) -> PathBuf {
+    path.with_extension(format!("{generation}.{segment}"))
+}
+
+/// Embedding model of the database saved at `path`
+pub(crate) fn saved_model(path: &Path) -> Result<String, OpenError> {
+    read_manifest(path).map(|manifest| manifest.model)
+}
+
+/// Checks the header before decoding the rest, a database saved in another format is never decoded as this one
+fn read_manifest(path: &Path) -> Result<Manifest, OpenError> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut header = [0; 8];
+    // files saved before the header existed can be shorter than it
+    if reader.read_exact(&mut header).is_err() || &header[..4] != MAGIC {
+        return Err(OpenError::UnknownFormat);
+    }
+
+    match u32::from_le_bytes(header[4..].try_into().unwrap()) {
+        FORMAT_VERSION => Ok(bincode::deserialize_from(reader)?),
+        version => Err(OpenError::UnsupportedVersion(version)),
+    }
+}
+
+/// Generations of the backups of the database at `path`, oldest first
+pub(crate) fn backup_generations(path: &Path) -> Vec<u64> {
+    generations(path, |extension| extension == BACKUP)
+}
+
+/// Generations that still have segment files next to the database at `path`
+fn segment_generations(path: &Path) -> Vec<u64> {
+    generations(path, |extension| SEGMENTS.contains(&extension))
+}
+
+/// Generations of the files named `<database>.<generation>.<extension>` next to `path`, sorted
+fn generations(path: &Path, extension: impl Fn(&str) -> bool) -> Vec<u64> {
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let mut generations = std::fs::read_dir(directory)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().to_string_lossy().to_string();
+            let (generation, file_extension) = name
+                .strip_prefix(&*stem)?
+                .strip_prefix('.')?
+                .split_once('.')?;
+
+            if extension(file_extension) {
+                generation.parse().ok()
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<u64>>();
+
+    generations.sort_unstable();
+    generations.dedup();
+
+    generations
+}
+
+/// Makes a rename in the directory of `path` durable, only possible on unix
+pub(crate) fn sync_directory(path: &Path) {
+    #[cfg(unix)]
+    if let Some(directory) = path
+        .parent()
+        .filter(|directory| !directory.as_os_str().is_empty())
+    {
+        File::open(directory).unwrap().sync_all().unwrap();
+    }
+}
+
+/// Copies `from` and syncs the copy so it survives a crash once the manifest is replaced
+fn copy_synced(from: &Path, to: &Path) {
+    std::fs::copy(from, to).unwrap();
+    File::open(to).unwrap().sync_all().unwrap();
+}
+
+pub(crate) fn map_segment(path: &Path) -> std::io::Result<Arc<Mmap>> {
+    let file = File::open(path)?;
+
+    // segment files are never written to once the manifest pointing to them is saved,
+    // saving writes a new generation of segments instead
+    Ok(Arc::new(unsafe { Mmap::map(&file) }?))
+}
+
+pub(crate) fn whole(segment: &Arc<Mmap>) -> Bytes {
+    Bytes::Mapped {
+        segment: segment.clone(),
+        range: 0..segment.len(),
+    }
+}
+
+struct SegmentWriter {
+    file: BufWriter<File>,
+    len: usize,
+}
+
+impl SegmentWriter {
+    fn create(path: &Path) -> SegmentWriter {
+        SegmentWriter {
+            file: BufWriter::new(File::create(path).unwrap()),
+            len: 0,
+        }
+    }
+
+    /// Returns where `bytes` are in the segment
+    fn write(&mut self, bytes: &[u8]) -> Range<usize> {
+        self.file.write_all(bytes).unwrap();
+
+        let start = self.len;
+        self.len += bytes.len();
+
+        start..self.len
+    }
+
+    fn finish(mut self) {
+        self.file.flush().unwrap();
+        self.file.get_ref().sync_all().unwrap();
+    }
+}
+
+impl VectorDB {
+    /// Replaces the documents and the index by the ones saved at `path`, the embedder and the cache are kept
+    ///
+    /// Only the manifest is read, the other segments are memory-mapped and decoded when used.
+    /// The segment files are opened read-only so several processes can share the same database.
+    pub(crate) fn load(&mut self, path: impl AsRef<Path>) -> Result<(), OpenError> {
+        let path = path.as_ref();
+
+        let manifest = read_manifest(path)?;
+        let generation = manifest.generation;
+
+        let texts = map_segment(&segment_path(path, generation, TEXTS))?;
+        let word_counts = map_segment(&segment_path(path, generation, WORD_COUNTS))?;
+        let index = map_segment(&segment_path(path, generation, INDEX))?;
+        let vectors = map_segment(&segment_path(path, generation, VECTORS))?;
+        let documents = map_segment(&segment_path(path, generation, DOCUMENTS))?;
+        let hashes = map_segment(&segment_path(path, generation, HASHES))?;
+        let graph = map_segment(&segment_path(path, generation, GRAPH))?;
+
+        let total_word_count = Bytes::Mapped {
+            segment: word_counts.clone(),
+            range: manifest.total_word_count,
+        };
+
+        self.documents = Lazy::mapped_with(whole(&documents), move |bytes| {
+            let entries: Slab<DocumentEntry> = bincode::deserialize(bytes).unwrap();
+
+            // checked once so reading a text doesn't check it again, see `Text`
+            let all_texts = std::str::from_utf8(&texts).expect("texts segment isn't UTF-8");
+            for (_, entry) in &entries {
+                assert!(
+                    all_texts.is_char_boundary(entry.text.start)
+                        && all_texts.is_char_boundary(entry.text.end)
+                        && entry.text.start <= entry.text.end,
+                    "text out of the texts segment"
+                );
+            }
+
+            let mapped = |segment: &Arc<Mmap>, range: Range<usize>| Bytes::Mapped {
+                segment: segment.clone(),
+                range,
+            };
+
+            entries
+                .into_iter()
+                .map(|(key, entry)| {
+                    let document = Document {
+                        text: Text(mapped(&texts, entry.text)),
+                        metadata: entry.metadata,
+                        individual_word_count: WordCount(
+                            fst::Map::new(mapped(&word_counts, entry.individual_word_count))
+                                .unwrap(),
+                        ),
+                        word_count: entry.word_count,
+                        language: entry.language,
+                        rows: entry.rows,
+                        sections: entry.sections,
+                    };
+
+                    (key, document)
+                })
+                .collect()
+        });
+
+        self.saved_word_count = Some(WordCount(
+            fst::Map::new(total_word_count).map_err(|_| OpenError::UnknownFormat)?,
+        ));
+        self.total_word_count.clear();
+        self.average_word_count = manifest.average_word_count;
+        self.section_size = manifest.section_size;
+        self.file_hashes = Lazy::mapped(whole(&hashes));
+        self.full_vectors = match manifest.full_vectors {
+            Some(dimensions) => {
+                let segment = map_segment(&segment_path(path, generation, FULL_VECTORS))?;
+
+                Some(FullVectors::mapped(Rows::mapped(
+                    whole(&segment),
+                    dimensions * std::mem::size_of::<f32>(),
+                )))
+            }
+            None => None,
+        };
+        self.graph = Lazy::mapped(whole(&graph));
+        let quantization = manifest.quantization;
+        let rows = Rows::mapped(whole(&vectors), manifest.vector_size);
+        self.vectors = rows.clone();
+        self.map = Lazy::mapped_with(whole(&index), move |bytes| {
+            VectorIndex::decode(bytes, &rows, quantization)
+        });
+        self.generation = Some(generation);
+        self.vectors_model = Some(manifest.model);
+
+        Ok(())
+    }
+
+    /// Adds the documents of a database saved by the first version of the crate and saves them in the current format
+    ///
+    /// The old file is kept next to the database, e.g. `./resources/database.legacy`.
+    pub(crate) fn migrate(&mut self, path: &Path, texts: Vec<String>) {
+        println!(
+            "Migrating the database to format {FORMAT_VERSION}, encoding its {} documents again",
+            texts.len()
+        );
+
+        std::fs::copy(path, path.with_extension("legacy")).unwrap();
+
+        for text in texts {
+            if let Some(encoded) = self.encode_document(&text) {
+                self.add_document(text, Metadata::default(), encoded);
+            }
+        }
+
+        self.save(path);
+    }
+
+    /// Writes a new generation of segments then the manifest, and loads them back
+    ///
+    /// Every file is synced to disk before the manifest is replaced, last, so a crash while saving
+    /// leaves the previous database intact. The previous manifest is kept as a backup with its segments,
+    /// only the `backups` most recent ones are kept.
+    pub(crate) fn save(&mut self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+
+        let mut texts = SegmentWriter::create(&segment_path(path, generation, TEXTS));
+        let mut word_counts = SegmentWriter::create(&segment_path(path, generation, WORD_COUNTS));
+
+        let documents = self
+            .documents
+            .iter()
+            .map(|(key, document)| {
+                let entry = DocumentEntry {
+                    metadata: document.metadata.clone(),
+                    word_count: document.word_count,
+                    language: document.language,
+                    text: texts.write(document.text.as_bytes()),
+                    individual_word_count: word_counts
+                        .write(document.individual_word_count.0.as_fst().as_bytes()),
+                    rows: document.rows.clone(),
+                    sections: document.sections.clone(),
+                };
+
+                (key, entry)
+            })
+            .collect::<Slab<_>>();
+
+        let mut total_word_count = fst::MapBuilder::memory();
+        total_word_count.extend_iter(self.word_counts()).unwrap();
+        let total_word_count = word_counts.write(&total_word_count.into_inner().unwrap());
+
+        texts.finish();
+        word_counts.finish();
+
+        // the saved index points to rows by their position, the rows added since follow the saved ones
+        let mut index = SegmentWriter::create(&segment_path(path, generation, INDEX));
+        self.map.write(&mut index.file);
+        index.finish();
+
+        let mut vectors = SegmentWriter::create(&segment_path(path, generation, VECTORS));
+        self.vectors.write(&mut vectors.file);
+        vectors.finish();
+
+        let mut entries = SegmentWriter::create(&segment_path(path, generation, DOCUMENTS));
+        bincode::serialize_into(&mut entries.file, &documents).unwrap();
+        entries.finish();
+
+        let mut hashes = SegmentWriter::create(&segment_path(path, generation, HASHES));
+        self.file_hashes.write(&mut hashes.file);
+        hashes.finish();
+
+        let mut graph = SegmentWriter::create(&segment_path(path, generation, GRAPH));
+        self.graph.write(&mut graph.file);
+        graph.finish();
+
+        if let Some(full_vectors) = &self.full_vectors {
+            let mut segment = SegmentWriter::create(&segment_path(path, generation, FULL_VECTORS));
+            full_vectors.rows().write(&mut segment.file);
+            segment.finish();
+        }
+
+        let manifest = Manifest {
+            model: self.embedder.id().to_string(),
+            generation,
+            total_word_count,
+            average_word_count: self.average_word_count,
+            section_size: self.section_size,
+            quantization: self.map.quantization(),
+            vector_size: self.vectors.size(),
+            full_vectors: self.full_vectors.as_ref().map(FullVectors::dimensions),
+        };
+
+        let temporary_path = path.with_extension("tmp");
+        let mut manifest_file = SegmentWriter::create(&temporary_path);
+        manifest_file.write(MAGIC);
+        manifest_file.write(&FORMAT_VERSION.to_le_bytes());
+        bincode::serialize_into(&mut manifest_file.file, &manifest).unwrap();
+        manifest_file.finish();
+
+        if let Some(previous) = self.generation.filter(|_| self.backups > 0) {
+            copy_synced(path, &segment_path(path, previous, BACKUP));
+        }
+
+        std::fs::rename(temporary_path, path).unwrap();
+        sync_directory(path);
+
+        self.remove_old_generations(path, generation);
+
+        // the segments were just written and synced
+        self.load(path).unwrap();
+    }
+
+    /// Keeps the segments of the current generation and of the `backups` most recent backups
+    fn remove_old_generations(&self, path: &Path, current: u64) {
+        let mut backups = backup_generations(path);
+        // most recent first
+        backups.reverse();
+
+        for &backup in backups.iter().skip(self.backups) {
+            let _ = std::fs::remove_file(segment_path(path, backup, BACKUP));
+        }
+        backups.truncate(self.backups);
+
+        // other processes may still have the previous segments mapped,
+        // on unix the files only go away once they're unmapped, elsewhere removing them can fail
+        for generation in segment_generations(path) {
+            if generation != current && !backups.contains(&generation) {
+                for segment in SEGMENTS {
+                    let _ = std::fs::remove_file(segment_path(path, generation, segment));
+                }
+            }
+        }
+    }
+
+    /// Replaces the database saved at `path` by one of its backups and loads it
+    ///
+    /// The current save becomes a backup itself so restoring can be undone.
+    pub(crate) fn restore(&mut self, path: &Path, generation: u64) -> bool {
+        let backup = segment_path(path, generation, BACKUP);
+
+        // a backup saved by another version of the crate can't be loaded
+        if read_manifest(&backup).is_err() {
+            return false;
+        }
+
+        let temporary_path = path.with_extension("tmp");
+        copy_synced(&backup, &temporary_path);
+
+        if let Some(current) = self.generation {
+            copy_synced(path, &segment_path(path, current, BACKUP));
+        }
+
+        std::fs::rename(temporary_path, path).unwrap();
+        sync_directory(path);
+
+        self.load(path).is_ok()
+    }
+
+    /// Word count of the whole database, saved and added since
+    pub(crate) fn word_counts(&self) -> BTreeMap<Vec<u8>, u64> {
+        let mut counts: BTreeMap<Vec<u8>, i64> = BTreeMap::new();
+
+        if let Some(saved_word_count) = &self.saved_word_count {
+            let mut stream = saved_word_count.0.stream();
+
+            while let Some((word, count)) = stream.next() {
+                counts.insert(word.to_vec(), count as i64);
+            }
+        }
+
+        for (word, count) in &self.total_word_count {
+            *counts.entry(word.as_bytes().to_vec()).or_default() += count;
+        }
+
+        // words of removed documents
+        counts
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(word, count)| (word, count as u64))
+            .collect()
+    }
+}
@@ -0,0 +1,295 @@
+use crate::{chunks, Index, Metadata, CHARACTERS_PER_CHUNK, CHUNK_OVERLAP, DISTANCE_THRESHOLD};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    net::SocketAddr,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+    sync::Arc,
+};
+use tiny_http::{Header, Method, Request, Response};
+
+const DEFAULT_TOP_K: usize = 5;
+/// Larger `top_k` are lowered to it
+const MAX_TOP_K: usize = 100;
+/// With filters, this many times more documents are searched before filtering
+const FILTERED_CANDIDATES: usize = 10;
+
+#[derive(Deserialize)]
+struct SearchRequest {
+    query: String,
+    #[serde(default = "default_top_k")]
+    top_k: usize,
+    #[serde(default = "default_max_distance")]
+    max_distance: f32,
+    #[serde(default)]
+    filters: Filters,
+}
+
+fn default_top_k() -> usize {
+    DEFAULT_TOP_K
+}
+
+fn default_max_distance() -> f32 {
+    DISTANCE_THRESHOLD
+}
+
+#[derive(Default, Deserialize)]
+struct Filters {
+    /// Only keeps documents from this source, a file name matches its full path
+    source: Option<String>,
+}
+
+impl Filters {
+    fn is_empty(&self) -> bool {
+        self.source.is_none()
+    }
+
+    fn matches(&self, metadata: &Metadata) -> bool {
+        self.source.as_ref().is_none_or(|source| {
+            metadata.source == *source || Path::new(&metadata.source).ends_with(source)
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct DocumentRequest {
+    text: String,
+    #[serde(default)]
+    source: String,
+}
+
+/// JSON API over an `Index`, for scripts and editor plugins
+///
+/// - `POST /search` `{"query": "...", "top_k": 5, "max_distance": 0.3, "filters": {"source": "wires.md"}}`,
+///   the cards of the entities of the query are returned next to the results
+/// - `POST /documents` `{"text": "...", "source": "notes.md"}`, long texts are split in chunks
+/// - `DELETE /documents/{id}`
+/// - `GET /stats`
+///
+/// The database is saved after each change.
+pub struct Server {
+    server: tiny_http::Server,
+    index: Arc<Index>,
+}
+
+impl Server {
+    /// Listens on `address`, e.g. `127.0.0.1:5002`, port 0 picks a free one
+    pub fn bind(index: Arc<Index>, address: &str) -> Server {
+        Server {
+            server: tiny_http::Server::http(address).unwrap(),
+            index,
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.server.server_addr().to_ip().unwrap()
+    }
+
+    /// Answers requests until the process stops, each one on its own thread
+    pub fn run(&self) {
+        for request in self.server.incoming_requests() {
+            let index = self.index.clone();
+
+            std::thread::spawn(move || respond(&index, request));
+        }
+    }
+}
+
+fn respond(index: &Index, mut request: Request) {
+    let mut body = String::new();
+
+    let (status, response) = match request.as_reader().read_to_string(&mut body) {
+        // a panicking request answers 500 instead of leaving the client waiting
+        Ok(_) => catch_unwind(AssertUnwindSafe(|| {
+            handle(index, request.method(), request.url(), &body)
+        }))
+        .unwrap_or_else(|_| (500, json!({ "error": "internal error" }))),
+        Err(error) => (400, json!({ "error": error.to_string() })),
+    };
+
+    let response = Response::from_string(response.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+
+    // the client can be gone already, there's no one left to tell
+    let _ = request.respond(response);
+}
+
+/// Status code and body of the response
+fn handle(index: &Index, method: &Method, url: &str, body: &str) -> (u16, Value) {
+    let path = url.split('?').next().unwrap_or_default();
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    match (method, segments.as_slice()) {
+        (Method::Post, ["search"]) => {
+            let request: SearchRequest = match serde_json::from_str(body) {
+                Ok(request) => request,
+                Err(error) => return (400, json!({ "error": error.to_string() })),
+            };
+
+            let top_k = request.top_k.min(MAX_TOP_K);
+            let candidates = if request.filters.is_empty() {
+                top_k
+            } else {
+                top_k.saturating_mul(FILTERED_CANDIDATES)
+            };
+
+            let mut results = index.search(&request.query, candidates, request.max_distance);
+            results.retain(|result| request.filters.matches(&result.metadata));
+            results.truncate(top_k);
+
+            let entities = index.entity_cards(&request.query);
+
+            (200, json!({ "results": results, "entities": entities }))
+        }
+        (Method::Post, ["documents"]) => {
+            let request: DocumentRequest = match serde_json::from_str(body) {
+                Ok(request) => request,
+                Err(error) => return (400, json!({ "error": error.to_string() })),
+            };
+
+            let ids = chunks(&request.text, CHARACTERS_PER_CHUNK, CHUNK_OVERLAP)
+                .into_iter()
+                .enumerate()
+                .filter_map(|(position, chunk)| {
+                    index.add_with_metadata(
+                        chunk,
+                        Metadata {
+                            source: request.source.clone(),
+                            position,
+                            children: Vec::new(),
+                            table: None,
+                            timestamp: None,
+                        },
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            if !ids.is_empty() {
+                index.save();
+            }
+
+            (200, json!({ "ids": ids }))
+        }
+        (Method::Delete, ["documents", id]) => {
+            let Ok(id) = id.parse() else {
+                return (400, json!({ "error": format!("invalid document id {id}") }));
+            };
+
+            if index.remove_document(id) {
+                index.save();
+
+                (200, json!({ "removed": id }))
+            } else {
+                (404, json!({ "error": format!("no document {id}") }))
+            }
+        }
+        (Method::Get, ["stats"]) => (200, json!(index.stats())),
+        _ => (
+            404,
+            json!({ "error": format!("no route for {method} {path}") }),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HashEmbedder;
+
+    /// Serves an empty in memory index on a free port of localhost, returns its url
+    fn serve() -> String {
+        let index = Arc::new(Index::in_memory(Box::new(HashEmbedder::default())));
+        let server = Server::bind(index, "127.0.0.1:0");
+        let url = format!("http://{}", server.address());
+
+        std::thread::spawn(move || server.run());
+
+        url
+    }
+
+    /// Status code and body, error statuses included
+    fn call(request: ureq::Request, body: Option<Value>) -> (u16, Value) {
+        let result = match body {
+            Some(body) => request.send_string(&body.to_string()),
+            None => request.call(),
+        };
+
+        let response = match result {
+            Ok(response) => response,
+            Err(ureq::Error::Status(_, response)) => response,
+            Err(error) => panic!("{error}"),
+        };
+
+        let status = response.status();
+
+        (
+            status,
+            serde_json::from_str(&response.into_string().unwrap()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn adds_searches_and_removes_documents() {
+        let url = serve();
+
+        let (status, added) = call(
+            ureq::post(&format!("{url}/documents")),
+            Some(
+                json!({ "text": "Cut the red wire when there are two blue wires.", "source": "wires.md" }),
+            ),
+        );
+        assert_eq!(status, 200);
+        let id = added["ids"][0].as_u64().unwrap();
+
+        let (status, found) = call(
+            ureq::post(&format!("{url}/search")),
+            Some(json!({
+                "query": "which wire do I cut with two blue wires",
+                "max_distance": 2.0,
+                "filters": { "source": "wires.md" }
+            })),
+        );
+        assert_eq!(status, 200);
+        assert_eq!(found["results"].as_array().unwrap().len(), 1);
+
+        let (status, stats) = call(ureq::get(&format!("{url}/stats")), None);
+        assert_eq!(status, 200);
+        assert_eq!(stats["documents"], 1);
+
+        let (status, _) = call(ureq::delete(&format!("{url}/documents/{id}")), None);
+        assert_eq!(status, 200);
+
+        let (status, _) = call(ureq::delete(&format!("{url}/documents/{id}")), None);
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let url = serve();
+
+        let (status, _) = call(
+            ureq::post(&format!("{url}/search")),
+            Some(json!({ "top_k": 5 })),
+        );
+        assert_eq!(status, 400);
+
+        let (status, _) = call(ureq::get(&format!("{url}/nothing")), None);
+        assert_eq!(status, 404);
+
+        // would overflow when multiplied for the filters
+        let (status, _) = call(
+            ureq::post(&format!("{url}/search")),
+            Some(json!({
+                "query": "which wire do I cut",
+                "top_k": usize::MAX,
+                "filters": { "source": "wires.md" }
+            })),
+        );
+        assert_eq!(status, 200);
+    }
+}
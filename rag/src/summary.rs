@@ -0,0 +1,295 @@
+use crate::{BertEmbeddings, Index, Metadata, TextGenerator};
+use std::fmt::Write;
+
+/// Source of the summary documents, their position is their level in the tree starting at 1
+pub const SUMMARY_SOURCE: &str = "summary";
+/// Average number of documents summarized together
+const CLUSTER_SIZE: usize = 6;
+const CLUSTERING_ITERATIONS: usize = 10;
+/// Characters of the documents put in the prompt of a summary, shared between them
+const SUMMARY_INPUT: usize = 6000;
+/// Tokens
+const SUMMARY_LENGTH: u32 = 200;
+
+impl Index {
+    /// Builds a tree of summaries over the documents, up to `levels` levels, returns the number of summaries added
+    ///
+    /// Documents close to each other are clustered and `generator` summarizes each cluster,
+    /// then the summaries are clustered and summarized in turn.
+    /// Summaries are documents from `SUMMARY_SOURCE` with their `children` in their metadata,
+    /// so questions about a whole manual can hit a summary while precise ones still hit the leaves.
+    /// Previous summaries are replaced, they aren't updated when documents are added or removed.
+    pub fn build_summaries(&self, generator: &dyn TextGenerator, levels: usize) -> usize {
+        for summary in self.summaries() {
+            self.remove_document(summary);
+        }
+
+        let mut nodes = self
+            .read()
+            .documents
+            .iter()
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let mut added = 0;
+
+        for level in 1..=levels {
+            // documents without vectors can't be clustered
+            let centroids = self.read().document_centroids(&nodes);
+            nodes.retain(|node| centroids.contains_key(node));
+
+            if nodes.len() <= 1 {
+                break;
+            }
+
+            let embeddings = nodes
+                .iter()
+                .map(|node| &centroids[node])
+                .collect::<Vec<_>>();
+            let clusters = cluster(&embeddings, nodes.len().div_ceil(CLUSTER_SIZE));
+
+            let mut next_nodes = Vec::new();
+            let mut summaries = 0;
+            for cluster in clusters {
+                let children = cluster
+                    .into_iter()
+                    .map(|position| nodes[position])
+                    .collect::<Vec<_>>();
+
+                if children.len() == 1 {
+                    next_nodes.extend(children);
+                    continue;
+                }
+
+                let summary = self.summarize(generator, &children);
+
+                let metadata = Metadata {
+                    source: SUMMARY_SOURCE.to_string(),
+                    position: level,
+                    children: children.clone(),
+                    table: None,
+                    timestamp: None,
+                };
+
+                match self.add_with_metadata(summary, metadata) {
+                    Some(index) => {
+                        next_nodes.push(index);
+                        summaries += 1;
+                    }
+                    None => next_nodes.extend(children),
+                }
+            }
+
+            println!(
+                "Summary level {level}: {summaries} summaries of {} documents",
+                nodes.len()
+            );
+
+            added += summaries;
+
+            if summaries == 0 {
+                break;
+            }
+
+            nodes = next_nodes;
+        }
+
+        added
+    }
+
+    /// Indices of the summaries built by `build_summaries`
+    fn summaries(&self) -> Vec<usize> {
+        self.read()
+            .documents
+            .iter()
+            .filter(|(_, document)| !document.metadata.children.is_empty())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    fn summarize(&self, generator: &dyn TextGenerator, children: &[usize]) -> String {
+        let characters_per_child = SUMMARY_INPUT / children.len();
+
+        let mut passages = String::new();
+        {
+            let database = self.read();
+
+            for child in children {
+                if let Some(document) = database.documents.get(*child) {
+                    let text = document
+                        .text
+                        .chars()
+                        .take(characters_per_child)
+                        .collect::<String>();
+
+                    writeln!(passages, "{}\n", text.trim()).unwrap();
+                }
+            }
+        }
+
+        let prompt = format!(
+            "Summarize the passages below in a few sentences. \
+            Mention the topics, names and parts they cover. \
+            Only reply with the summary.\n\n\
+            {passages}"
+        );
+
+        generator
+            .generate(&prompt, SUMMARY_LENGTH)
+            .trim()
+            .to_string()
+    }
+}
+
+/// Spherical k-means, returns `count` clusters of positions in `embeddings`, empty ones are dropped
+fn cluster(embeddings: &[&BertEmbeddings], count: usize) -> Vec<Vec<usize>> {
+    // documents of the same source are usually next to each other, evenly spaced ones are different enough to start with
+    let mut centers = (0..count)
+        .map(|cluster| embeddings[cluster * embeddings.len() / count].clone())
+        .collect::<Vec<_>>();
+
+    let mut assignments: Vec<usize> = Vec::new();
+
+    for _ in 0..CLUSTERING_ITERATIONS {
+        let new_assignments = embeddings
+            .iter()
+            .map(|embedding| {
+                (0..count)
+                    .max_by(|&cluster1, &cluster2| {
+                        embedding
+                            .cosine_similarity(&centers[cluster1])
+                            .total_cmp(&embedding.cosine_similarity(&centers[cluster2]))
+                    })
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        if new_assignments == assignments {
+            break;
+        }
+
+        assignments = new_assignments;
+
+        for (cluster, center) in centers.iter_mut().enumerate() {
+            let mut sum = vec![0.0; center.0.len()];
+
+            for (embedding, _) in embeddings
+                .iter()
+                .zip(&assignments)
+                .filter(|(_, assignment)| **assignment == cluster)
+            {
+                for (sum, value) in sum.iter_mut().zip(&embedding.0) {
+                    *sum += value;
+                }
+            }
+
+            // only the direction matters for the cosine similarity
+            if sum.iter().any(|value| *value != 0.0) {
+                *center = BertEmbeddings(sum);
+            }
+        }
+    }
+
+    let mut clusters = vec![Vec::new(); count];
+    for (position, cluster) in assignments.into_iter().enumerate() {
+        clusters[cluster].push(position);
+    }
+
+    clusters.retain(|cluster| !cluster.is_empty());
+
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HashEmbedder;
+    use std::{cell::RefCell, collections::HashSet};
+
+    /// Numbers its summaries, documents with the same text can't be added twice
+    #[derive(Default)]
+    struct StubGenerator {
+        /// Each prompt and the summary generated for it
+        prompts: RefCell<Vec<(String, String)>>,
+    }
+
+    impl TextGenerator for StubGenerator {
+        fn generate(&self, prompt: &str, _max_length: u32) -> String {
+            let mut prompts = self.prompts.borrow_mut();
+            let summary = format!("Summary number {} of the passages.", prompts.len() + 1);
+            prompts.push((prompt.to_string(), summary.clone()));
+
+            summary
+        }
+    }
+
+    #[test]
+    fn summaries_form_a_tree_over_the_documents() {
+        let index = Index::in_memory(Box::new(HashEmbedder::default()));
+
+        let mut leaves = Vec::new();
+        for module in 0..15 {
+            let text = if module % 2 == 0 {
+                format!("Wires module {module}. Cut the red wire when there are {module} wires.")
+            } else {
+                format!(
+                    "Keypad module {module}. Press the symbols in the order of column {module}."
+                )
+            };
+
+            leaves.extend(index.add(text));
+        }
+
+        let generator = StubGenerator::default();
+        let added = index.build_summaries(&generator, 2);
+
+        assert!(added > 0);
+        assert_eq!(generator.prompts.borrow().len(), added);
+
+        let database = index.read();
+        let summaries = database
+            .documents
+            .iter()
+            .filter(|(_, document)| document.metadata.source == SUMMARY_SOURCE)
+            .collect::<Vec<_>>();
+        assert_eq!(summaries.len(), added);
+
+        let mut children = HashSet::new();
+        for (_, summary) in &summaries {
+            let metadata = &summary.metadata;
+
+            assert!((1..=2).contains(&metadata.position));
+            assert!(metadata.children.len() > 1);
+
+            for child in &metadata.children {
+                // a node is summarized once
+                assert!(children.insert(*child));
+
+                let child = &database.documents[*child];
+                if metadata.position == 1 {
+                    assert_ne!(child.metadata.source, SUMMARY_SOURCE);
+                } else if child.metadata.source == SUMMARY_SOURCE {
+                    assert_eq!(child.metadata.position, 1);
+                }
+            }
+        }
+
+        // every summary is built from the texts of its children
+        for (_, summary) in &summaries {
+            let prompts = generator.prompts.borrow();
+            let (prompt, _) = prompts
+                .iter()
+                .find(|(_, generated)| *generated == *summary.text)
+                .unwrap();
+
+            for child in &summary.metadata.children {
+                assert!(prompt.contains(database.documents[*child].text.trim()));
+            }
+        }
+
+        drop(database);
+
+        // building them again replaces them
+        let rebuilt = index.build_summaries(&StubGenerator::default(), 1);
+        assert_eq!(index.summaries().len(), rebuilt);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{chunks, Index, Metadata};
+use serde::{Deserialize, Serialize};
+
+/// Consecutive aligned lines of a pdf needed to take them as a table
+const MIN_TABLE_ROWS: usize = 3;
+/// Characters a column can be off by between two lines of a pdf table
+const ALIGNMENT_TOLERANCE: usize = 1;
+
+/// A row of a table with its column headers, stored as its own document
+///
+/// The row is searched as "Wire color: red; Action: cut the second wire"
+/// and shown in the context as a markdown table.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TableRow {
+    pub headers: Vec<String>,
+    pub cells: Vec<String>,
+}
+
+impl TableRow {
+    /// Text of the document, each cell is next to its header
+    pub fn to_text(&self) -> String {
+        self.headers
+            .iter()
+            .zip(&self.cells)
+            .filter(|(_, cell)| !cell.is_empty())
+            .map(|(header, cell)| {
+                if header.is_empty() {
+                    cell.clone()
+                } else {
+                    format!("{header}: {cell}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    pub fn to_markdown(&self) -> String {
+        markdown_table(&self.headers, std::slice::from_ref(&self.cells))
+    }
+}
+
+/// A table removed from the text of a document
+pub(crate) struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+    /// Where the table was in the text left
+    position: usize,
+}
+
+pub(crate) fn markdown_table(headers: &[String], rows: &[Vec<String>]) -> String {
+    let line = |cells: &[String]| format!("| {} |", cells.join(" | "));
+
+    let mut table = vec![line(headers), line(&vec!["---".to_string(); headers.len()])];
+    table.extend(rows.iter().map(|row| line(row)));
+
+    table.join("\n")
+}
+
+/// Cells of a markdown table line, the outer pipes are optional
+fn cells(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = line.strip_suffix('|').unwrap_or(line);
+
+    line.split('|')
+        .map(|cell| cell.trim().to_string())
+        .collect()
+}
+
+/// `| --- | :---: |`
+fn is_separator(line: &str) -> bool {
+    line.contains('-')
+        && cells(line)
+            .iter()
+            .all(|cell| !cell.is_empty() && cell.chars().all(|c| c == '-' || c == ':'))
+}
+
+/// Removes the markdown tables of `text`, returns the text left and the tables
+pub(crate) fn extract_tables(text: &str) -> (String, Vec<Table>) {
+    let lines = text.split_inclusive('\n').collect::<Vec<_>>();
+
+    let mut prose = String::new();
+    let mut tables = Vec::new();
+
+    let mut line = 0;
+    while line < lines.len() {
+        if lines[line].contains('|') && lines.get(line + 1).is_some_and(|next| is_separator(next)) {
+            let headers = cells(lines[line]);
+
+            let mut rows = Vec::new();
+            line += 2;
+            while line < lines.len() && lines[line].contains('|') {
+                let mut row = cells(lines[line]);
+                row.resize(headers.len(), String::new());
+                rows.push(row);
+
+                line += 1;
+            }
+
+            tables.push(Table {
+                headers,
+                rows,
+                position: prose.len(),
+            });
+        } else {
+            prose.push_str(lines[line]);
+
+            line += 1;
+        }
+    }
+
+    (prose, tables)
+}
+
+/// Cells of a line of a pdf table, with the character each one starts at
+struct Columns {
+    cells: Vec<String>,
+    offsets: Vec<usize>,
+    /// All the cells are separated by tabs
+    tabs: bool,
+}
+
+impl Columns {
+    /// Columns are separated by a tab or at least 2 spaces
+    fn of(line: &str) -> Columns {
+        let mut columns = Columns {
+            cells: Vec::new(),
+            offsets: Vec::new(),
+            tabs: true,
+        };
+
+        let chars = line.chars().collect::<Vec<_>>();
+        let mut start = None;
+        let mut position = 0;
+        while position <= chars.len() {
+            let blank = chars[position..]
+                .iter()
+                .take_while(|c| **c == ' ' || **c == '\t')
+                .count();
+            let at_end = position + blank == chars.len();
+
+            if blank == 0 && !at_end {
+                start.get_or_insert(position);
+                position += 1;
+
+                continue;
+            }
+
+            let tab = chars[position..position + blank].contains(&'\t');
+            if let Some(cell_start) = start.filter(|_| at_end || tab || blank >= 2) {
+                columns
+                    .cells
+                    .push(chars[cell_start..position].iter().collect());
+                columns.offsets.push(cell_start);
+                start = None;
+
+                if !at_end && !tab {
+                    columns.tabs = false;
+                }
+            }
+
+            position += blank.max(1);
+        }
+
+        columns
+    }
+
+    /// Same number of columns, separated by tabs in both or starting at the same characters
+    fn aligned_with(&self, other: &Columns) -> bool {
+        self.cells.len() == other.cells.len()
+            && ((self.tabs && other.tabs)
+                || self
+                    .offsets
+                    .iter()
+                    .zip(&other.offsets)
+                    .all(|(offset1, offset2)| offset1.abs_diff(*offset2) <= ALIGNMENT_TOLERANCE))
+    }
+}
+
+/// Turns lines of columns separated by tabs or aligned with spaces into markdown tables
+///
+/// Text extracted from a pdf loses the borders of its tables, at least `MIN_TABLE_ROWS` consecutive lines
+/// with the same columns are taken as a table, the first one being the headers. Columns separated by
+/// spaces have to start at the same character on every line, sentences with double spaces aren't tables.
+pub(crate) fn align_tables(text: &str) -> String {
+    let lines = text.lines().collect::<Vec<_>>();
+    let columns = lines
+        .iter()
+        .map(|line| Columns::of(line))
+        .collect::<Vec<_>>();
+    let mut output = Vec::new();
+
+    let mut line = 0;
+    while line < lines.len() {
+        let headers = &columns[line];
+
+        let mut end = line + 1;
+        if headers.cells.len() >= 2 && !lines[line].contains('|') {
+            while end < lines.len() && columns[end].aligned_with(headers) {
+                end += 1;
+            }
+        }
+
+        if end - line >= MIN_TABLE_ROWS {
+            let rows = columns[line + 1..end]
+                .iter()
+                .map(|columns| columns.cells.clone())
+                .collect::<Vec<_>>();
+
+            output.push(markdown_table(&headers.cells, &rows));
+        } else {
+            output.extend(lines[line..end].iter().map(|line| line.to_string()));
+        }
+
+        line = end;
+    }
+
+    output.join("\n")
+}
+
+impl Index {
+    /// Adds `markdown` in chunks of `chunk_size`, and each row of its tables as its own document
+    ///
+    /// Chunks are numbered from `first_position`, rows get the position of the chunk their table was in.
+    pub(crate) fn add_markdown(
+        &self,
+        markdown: &str,
+        source: &str,
+        chunk_size: usize,
+        overlap: usize,
+        first_position: usize,
+    ) {
+        let (prose, tables) = extract_tables(markdown);
+
+        let chunks = chunks(&prose, chunk_size, overlap);
+
+        // chunk containing each position of the text
+        let chunk_number = |position: usize| {
+            chunks
+                .iter()
+                .rposition(|chunk| chunk.as_ptr() as usize - prose.as_ptr() as usize <= position)
+                .unwrap_or_default()
+        };
+
+        for (chunk_number, chunk) in chunks.iter().enumerate() {
+            if !chunk.trim().is_empty() {
+                self.add_with_metadata(
+                    *chunk,
+                    Metadata {
+                        source: source.to_string(),
+                        position: first_position + chunk_number,
+                        children: Vec::new(),
+                        table: None,
+                        timestamp: None,
+                    },
+                );
+            }
+        }
+
+        for table in tables {
+            let position = first_position + chunk_number(table.position);
+
+            for cells in table.rows {
+                let row = TableRow {
+                    headers: table.headers.clone(),
+                    cells,
+                };
+
+                self.add_with_metadata(
+                    row.to_text(),
+                    Metadata {
+                        source: source.to_string(),
+                        position,
+                        children: Vec::new(),
+                        table: Some(row),
+                        timestamp: None,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_markdown_tables() {
+        let text = "Wires\n\n| Color | Action |\n| --- | :---: |\n| red | cut |\n| blue |\n\nThen the button.\n";
+
+        let (prose, tables) = extract_tables(text);
+
+        assert_eq!(prose, "Wires\n\n\nThen the button.\n");
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].headers, ["Color", "Action"]);
+        // missing cells are empty
+        assert_eq!(tables[0].rows, [["red", "cut"], ["blue", ""]]);
+        assert_eq!(tables[0].position, "Wires\n\n".len());
+    }
+
+    #[test]
+    fn pipes_without_separator_are_prose() {
+        let text = "Press | then release\nthe button\n";
+
+        let (prose, tables) = extract_tables(text);
+
+        assert_eq!(prose, text);
+        assert!(tables.is_empty());
+    }
+
+    #[test]
+    fn aligns_tab_separated_columns() {
+        let text = "Intro\nColor\tAction\nred\tcut the second wire\nblue\tcut the last wire\nOutro";
+
+        assert_eq!(
+            align_tables(text),
+            "Intro\n| Color | Action |\n| --- | --- |\n| red | cut the second wire |\n| blue | cut the last wire |\nOutro"
+        );
+    }
+
+    #[test]
+    fn aligns_columns_starting_at_the_same_character() {
+        let text = "Label   Lit   Action\nFRK     yes   press\nCAR     no    hold";
+
+        assert_eq!(
+            align_tables(text),
+            "| Label | Lit | Action |\n| --- | --- | --- |\n| FRK | yes | press |\n| CAR | no | hold |"
+        );
+    }
+
+    #[test]
+    fn double_spaced_sentences_are_not_tables() {
+        let text = "Cut the wire.  Then press it.\nHold the button.  Release it on a 4.\nThe timer counts.  Watch it.";
+
+        assert_eq!(align_tables(text), text);
+    }
+
+    #[test]
+    fn two_lines_are_not_a_table() {
+        let text = "Color\tAction\nred\tcut";
+
+        assert_eq!(align_tables(text), text);
+    }
+}
@@ -1,10 +1,16 @@
-use crate::RAG;
+use crate::{table::markdown_table, Index, CHARACTERS_PER_CHUNK, CHUNK_OVERLAP};
 use html5ever::interface::TreeSink;
 use shared::{END_OF_SENTENCE, SPLIT_WORD};
-use std::io::Write;
 
-impl RAG {
-    pub fn parse_website(&self, url: &str) {
+impl Index {
+    /// Adds the main content of the page at `url`, converted to markdown, the rows of its tables are documents of their own
+    pub fn add_website(&self, url: &str) {
+        let md = self.parse_website(url);
+
+        self.add_markdown(&md, url, CHARACTERS_PER_CHUNK, CHUNK_OVERLAP, 0);
+    }
+
+    pub fn parse_website(&self, url: &str) -> String {
         let site_string = ureq::get(url).call().unwrap().into_string().unwrap();
 
         let mut site = scraper::Html::parse_document(&site_string);
@@ -49,6 +55,32 @@ impl RAG {
             }
         }
 
+        // markdown conversion flattens tables, they're rendered separately and put back in place afterwards
+        let tables = site
+            .root_element()
+            .descendants()
+            .filter_map(scraper::ElementRef::wrap)
+            .filter(|element| {
+                element.value().name() == "table"
+                    && !element.ancestors().any(|ancestor| {
+                        ancestor
+                            .value()
+                            .as_element()
+                            .is_some_and(|element| element.name() == "table")
+                    })
+            })
+            .map(|table| (table.id(), html_table(table)))
+            .collect::<Vec<_>>();
+
+        for (number, (id, _)) in tables.iter().enumerate() {
+            let mut table = site.tree.get_mut(*id).unwrap();
+
+            table.insert_before(scraper::Node::Text(scraper::node::Text {
+                text: table_placeholder(number).into(),
+            }));
+            table.detach();
+        }
+
         let nodes_to_delete = site
             .root_element()
             .descendants()
@@ -99,9 +131,6 @@ impl RAG {
             should_keep
         });
 
-        let mut file = std::fs::File::create("stripped.html").unwrap();
-        file.write_all(stripped.as_bytes()).unwrap();
-
         let md = mdka::from_html(&stripped);
 
         let mut md = md
@@ -136,7 +165,49 @@ impl RAG {
             should_keep
         });
 
-        let mut file = std::fs::File::create("./stripped.md").unwrap();
-        file.write_all(md.as_bytes()).unwrap();
+        // after the clean up, it removes lines like "| --- |"
+        for (number, (_, table)) in tables.iter().enumerate() {
+            md = md.replace(&table_placeholder(number), &format!("\n{table}\n"));
+        }
+
+        md
+    }
+}
+
+fn table_placeholder(number: usize) -> String {
+    format!("TABLE{number}PLACEHOLDER")
+}
+
+/// Markdown table with the first row as headers, pipes in cells are replaced by slashes
+fn html_table(table: scraper::ElementRef) -> String {
+    let mut rows = table
+        .descendants()
+        .filter_map(scraper::ElementRef::wrap)
+        .filter(|element| element.value().name() == "tr")
+        .map(|row| {
+            row.children()
+                .filter_map(scraper::ElementRef::wrap)
+                .filter(|cell| matches!(cell.value().name(), "th" | "td"))
+                .map(|cell| {
+                    cell.text()
+                        .flat_map(str::split_whitespace)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                        .replace('|', "/")
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter(|row| !row.is_empty())
+        .collect::<Vec<_>>();
+
+    if rows.is_empty() {
+        return String::new();
     }
+
+    let headers = rows.remove(0);
+    for row in &mut rows {
+        row.resize(headers.len(), String::new());
+    }
+
+    markdown_table(&headers, &rows)
 }
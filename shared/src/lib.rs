@@ -1,3 +1,11 @@
 // TODO: handle "..."
 pub const END_OF_SENTENCE: &[char] = &['.', '!', '?', '\n', '\r', '…'];
 pub const SPLIT_WORD: &[char] = &[' ', ',', ':', '"'];
+/// Rough estimate for english text, good enough to budget the prompt
+pub const CHARACTERS_PER_TOKEN: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Speaker {
+    User,
+    Assistant,
+}
@@ -1,23 +1,54 @@
 use llm::{Model, Speaker, LLM};
-use rag::RAG;
-use std::io::stdin;
+use rag::{Memory, QueryRewriter, RetrievalMode, RAG};
+use std::{
+    io::{stdin, stdout, Write},
+    sync::Arc,
+};
 
 fn main() {
-    let mut rag = RAG::new();
+    // databases saved before the multilingual model stay English only, `rag reencode` converts them
+    let mut rag = match RAG::new() {
+        Ok(rag) => rag,
+        Err(error) => {
+            println!("Can't open the database: {error}");
+            return;
+        }
+    };
+    // rag.set_mmr_lambda(Some(0.7));
+    // rag.set_compression(Some(0.6));
+    // rag.set_parent_sections(true);
+    // rag.set_entity_cards(true);
+    // rag.set_grounded(true);
+    // rag.index().set_quantization(rag::Quantization::Int8, true);
+
+    let memory = match Memory::open(rag.index().embedder()) {
+        Ok(memory) => Arc::new(memory),
+        Err(error) => {
+            println!("Can't open the memory: {error}");
+            return;
+        }
+    };
+    rag.set_memory(Some(memory.clone()));
 
     // for file in
     //     std::fs::read_dir("./resources/KeepTalkingAndNobodyExplodes-BombDefusalManual-v1").unwrap()
     // {
     //     let file = file.unwrap();
 
-    //     rag.add_document(file.path());
+    //     rag.index().add_document(file.path());
     // }
 
-    // rag.save();
+    // rag.index().save();
 
     let mut llm = LLM::init(Model::Mistral, "Leudz", "Emma");
+    // let backend = llm::Ollama::new("http://localhost:11434", "mistral");
+    // let mut llm = LLM::with_backend(Box::new(backend), Model::Mistral, "Leudz", "Emma");
+    // llm.enable_tts();
     // llm.disable_tts();
 
+    // rag.index().build_summaries(&llm, 3);
+    // rag.index().save();
+
     // llm.history_mut().set_instruction(
     //     "- I'm going to describe you modules.\n\
     //      - You will ask me questions to collect details on the module.\n\
@@ -47,19 +78,44 @@ fn main() {
         } else if input == "skip" {
             llm.skip_tts();
             continue;
+        } else if let Some(query) = input.strip_prefix("explain ") {
+            println!("{}", rag.explain(query));
+            input.clear();
+            continue;
         }
 
-        if !llm.history_mut().instruction().is_empty() {
-            llm.history_mut().set_context(rag.update_context(&input));
+        if !llm.history().instruction().is_empty() {
+            let history = llm.history().entries().collect::<Vec<_>>();
+            // let rewriter = QueryRewriter::Generator(&llm);
+            let rewriter = QueryRewriter::Keywords;
+            // let mode = RetrievalMode::HyDE(&llm);
+            // let mode = RetrievalMode::MultiQuery(&llm, 3);
+            let mode = RetrievalMode::Direct;
+
+            rag.set_context_budget(Some(llm.context_budget()));
+            let context = rag.update_context_with_history(&input, &history, rewriter, mode);
+            llm.history_mut().set_context(context);
         }
 
-        llm.history_mut().add(input.clone(), Speaker::User);
+        let mut forgotten = llm.history_mut().add(input.clone(), Speaker::User);
         input.clear();
 
-        let reply = llm.send();
-
-        println!("> {reply}");
-
-        llm.history_mut().add(reply, Speaker::Assistant);
+        print!("> ");
+        let reply = llm.send_stream(|token| {
+            print!("{token}");
+            stdout().flush().unwrap();
+        });
+        println!();
+        // let reply = llm.send();
+        // println!("> {reply}");
+        // let answer = llm.send_grounded(&rag);
+        // println!("> {answer}");
+        // let reply = answer.text;
+
+        forgotten.extend(llm.history_mut().add(reply, Speaker::Assistant));
+
+        let history = llm.history();
+        memory.remember_turns(&forgotten, history.user(), history.assistant());
+        // memory.remember_facts(&llm, &forgotten, history.user(), history.assistant());
     }
 }